@@ -0,0 +1,280 @@
+use crate::packages::{Package, PackageExt};
+use crate::{schema, secret};
+use sha1::{Digest, Sha1};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+type Fragments = BTreeMap<(String, String), Vec<u8>>;
+
+// composes the fragments of every loaded package into the aggregates:
+// a file aggregate is a file of its owner, while a fragment in a drop-in directory
+// is a file of its contributor, so that installing or removing it leaves the owner alone.
+// packages kept as they were still own their aggregates and contribute their last fragments.
+pub fn compose<T>(
+    packages: &mut BTreeMap<String, Package>,
+    kept: &[&schema::Package<T>],
+) -> anyhow::Result<()> {
+    let mut owners = BTreeMap::<PathBuf, (String, schema::Aggregate)>::new();
+    let states = packages
+        .values()
+        .map(|package| (&package.name, &package.aggregates))
+        .chain(
+            kept.iter()
+                .map(|package| (&package.name, &package.aggregates)),
+        );
+    for (package_name, aggregates) in states {
+        for (path, aggregate) in aggregates {
+            if let Some((owner, _)) =
+                owners.insert(path.clone(), (package_name.clone(), *aggregate))
+            {
+                anyhow::bail!(
+                    "`{}` is aggregated by both `{owner}` and `{package_name}`",
+                    path.display(),
+                );
+            }
+        }
+    }
+
+    // ordered by key, then by contributor
+    let mut fragments = BTreeMap::<PathBuf, Fragments>::new();
+    for package in packages.values() {
+        for (target, contributed) in &package.fragments {
+            if !owners.contains_key(target) {
+                tracing::info!(package.name, ?target, "no aggregate, fragments skipped");
+                continue;
+            }
+            for (key, content) in contributed {
+                fragments
+                    .entry(target.clone())
+                    .or_default()
+                    .insert((key.clone(), package.name.clone()), content.clone());
+            }
+        }
+    }
+    for package in kept {
+        for (target, contributed) in &package.fragments {
+            if owners.contains_key(target) {
+                for (key, content) in contributed {
+                    fragments
+                        .entry(target.clone())
+                        .or_default()
+                        .insert((key.clone(), package.name.clone()), content.clone().into());
+                }
+            }
+        }
+    }
+
+    for (target, (owner, aggregate)) in owners {
+        let fragments = fragments.remove(&target).unwrap_or_default();
+        match aggregate {
+            schema::Aggregate::File => {
+                // the fragments are persisted by their contributors
+                for ((key, contributor), content) in &fragments {
+                    let Some(package) = packages.get_mut(contributor) else {
+                        continue;
+                    };
+                    let content = String::from_utf8(content.clone()).map_err(|_| {
+                        anyhow::format_err!(
+                            "fragment `{key}` of `{}` is not text",
+                            target.display(),
+                        )
+                    })?;
+                    anyhow::ensure!(
+                        matches!(secret::redact(&content), Cow::Borrowed(_)),
+                        "fragment `{key}` of `{}` cannot use a secret",
+                        target.display(),
+                    );
+                    package
+                        .state
+                        .fragments
+                        .entry(target.clone())
+                        .or_default()
+                        .insert(key.clone(), content);
+                }
+                // a kept owner keeps its file as it was
+                let Some(package) = packages.get_mut(&owner) else {
+                    continue;
+                };
+                anyhow::ensure!(
+                    !package.files.contains_key(&target),
+                    "`{}` is both a file and an aggregate of `{owner}`",
+                    target.display(),
+                );
+                let mut content = Vec::new();
+                for fragment in fragments.values() {
+                    content.extend_from_slice(fragment);
+                    if !content.is_empty() && !content.ends_with(b"\n") {
+                        content.push(b'\n');
+                    }
+                }
+                install(package, target, content, inputs(&fragments))?;
+            }
+            schema::Aggregate::Dir => {
+                let mut contributors = BTreeMap::new();
+                for ((key, contributor), content) in &fragments {
+                    if let Some(other) = contributors.insert(key, contributor) {
+                        anyhow::bail!(
+                            "fragment `{key}` of `{}` is contributed by both `{other}` and `{contributor}`",
+                            target.display(),
+                        );
+                    }
+                    // a kept contributor keeps its files as they were
+                    let Some(package) = packages.get_mut(contributor) else {
+                        continue;
+                    };
+                    let fragment =
+                        BTreeMap::from([((key.clone(), contributor.clone()), content.clone())]);
+                    let path = target.join(key);
+                    install(package, path.clone(), content.clone(), inputs(&fragment))?;
+                    package.state.dropins.insert(path);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn install(
+    package: &mut Package,
+    path: PathBuf,
+    content: Vec<u8>,
+    inputs: BTreeMap<String, String>,
+) -> anyhow::Result<()> {
+    // a fragment rendered from a secret keeps the aggregate private
+    let mode = matches!(
+        secret::redact(&String::from_utf8_lossy(&content)),
+        Cow::Owned(_)
+    )
+    .then_some(0o100600);
+    package.file(&path, content, mode)?;
+    if let Some(file) = package.files.get_mut(&path) {
+        file.inputs = inputs;
+    }
+    Ok(())
+}
+
+fn inputs(fragments: &Fragments) -> BTreeMap<String, String> {
+    fragments
+        .iter()
+        .map(|((key, contributor), content)| {
+            (
+                format!("fragment.{contributor}:{key}"),
+                hex::encode(Sha1::digest(content)),
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::path::Path;
+
+    const NONE_KEPT: &[&schema::Package<()>] = &[];
+
+    fn packages<const N: usize>(packages: [Package; N]) -> BTreeMap<String, Package> {
+        packages
+            .into_iter()
+            .map(|package| (package.name.clone(), package))
+            .collect()
+    }
+
+    #[test]
+    fn file() {
+        let mut owner = Package::new("owner");
+        owner
+            .aggregate("/agg/file", schema::Aggregate::File)
+            .unwrap();
+        let mut a = Package::new("a");
+        a.fragment("/agg/file", "20", "a").unwrap();
+        let mut b = Package::new("b");
+        b.fragment("/agg/file", "10", "b\n").unwrap();
+        b.fragment("/agg/other", "10", "b").unwrap();
+        let mut packages = packages([owner, a, b]);
+        compose(&mut packages, NONE_KEPT).unwrap();
+
+        let file = &packages["owner"].files[Path::new("/agg/file")];
+        assert_eq!(file.extra, b"b\na\n");
+        assert_eq!(
+            file.inputs.keys().collect::<Vec<_>>(),
+            ["fragment.a:20", "fragment.b:10"],
+        );
+        assert!(packages["a"].files.is_empty());
+        // fragments without an aggregate are not persisted
+        assert_eq!(
+            packages["b"].state.fragments,
+            BTreeMap::from([(
+                PathBuf::from("/agg/file"),
+                BTreeMap::from([("10".to_owned(), "b\n".to_owned())]),
+            )]),
+        );
+    }
+
+    #[test]
+    fn dir() {
+        let mut owner = Package::new("owner");
+        owner.aggregate("/agg/dir", schema::Aggregate::Dir).unwrap();
+        let mut a = Package::new("a");
+        a.fragment("/agg/dir", "10-a", "a").unwrap();
+        let mut packages = packages([owner, a]);
+        compose(&mut packages, NONE_KEPT).unwrap();
+
+        assert!(packages["owner"].files.is_empty());
+        assert_eq!(packages["a"].files[Path::new("/agg/dir/10-a")].extra, b"a");
+        assert!(packages["a"].state.fragments.is_empty());
+        assert_eq!(
+            packages["a"].dropins,
+            BTreeSet::from([PathBuf::from("/agg/dir/10-a")])
+        );
+    }
+
+    #[test]
+    fn kept() {
+        let mut owner = Package::new("owner");
+        owner
+            .aggregate("/agg/file", schema::Aggregate::File)
+            .unwrap();
+        let mut a = Package::new("a");
+        a.fragment("/agg/file", "20", "a").unwrap();
+        a.fragment("/agg/dir", "20", "a").unwrap();
+        let mut packages = packages([owner, a]);
+        // `b` contributes its last fragments, and `other` still owns its directory
+        let mut b = schema::Package::<()>::new("b");
+        b.fragments = BTreeMap::from([(
+            PathBuf::from("/agg/file"),
+            BTreeMap::from([("10".to_owned(), "b".to_owned())]),
+        )]);
+        let mut other = schema::Package::<()>::new("other");
+        other
+            .aggregates
+            .insert("/agg/dir".into(), schema::Aggregate::Dir);
+        compose(&mut packages, &[&b, &other]).unwrap();
+
+        assert_eq!(
+            packages["owner"].files[Path::new("/agg/file")].extra,
+            b"b\na\n"
+        );
+        assert!(packages["a"].files.contains_key(Path::new("/agg/dir/20")));
+    }
+
+    #[test]
+    fn conflicts() {
+        let mut owner = Package::new("owner");
+        owner.aggregate("/agg/dir", schema::Aggregate::Dir).unwrap();
+        let mut other = Package::new("other");
+        other
+            .aggregate("/agg/dir", schema::Aggregate::File)
+            .unwrap();
+        assert!(compose(&mut packages([owner, other]), NONE_KEPT).is_err());
+
+        let mut owner = Package::new("owner");
+        owner.aggregate("/agg/dir", schema::Aggregate::Dir).unwrap();
+        let mut a = Package::new("a");
+        a.fragment("/agg/dir", "10", "a").unwrap();
+        let mut b = Package::new("b");
+        b.fragment("/agg/dir", "10", "b").unwrap();
+        assert!(compose(&mut packages([owner, a, b]), NONE_KEPT).is_err());
+    }
+}
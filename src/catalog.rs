@@ -0,0 +1,105 @@
+use crate::{packages, schema};
+use std::collections::BTreeMap;
+
+pub fn list(state: &schema::State<()>) -> anyhow::Result<()> {
+    print(state, |_, _| true)
+}
+
+pub fn info(state: &schema::State<()>, package_name: &str) -> anyhow::Result<()> {
+    let mut entries = packages::packages()?;
+    let entry = entries.remove(package_name);
+    let installed = state
+        .packages
+        .iter()
+        .find(|package| package.name == package_name);
+    anyhow::ensure!(
+        entry.is_some() || installed.is_some(),
+        "missing package `{package_name}`",
+    );
+
+    println!("name: {package_name}");
+    println!("status: {}", status(entry.is_some(), installed.is_some()));
+    if let Some(entry) = &entry {
+        let metadata = &entry.metadata;
+        println!("description: {}", metadata.description);
+        println!("requires: {}", join(&metadata.requires));
+        println!("optional: {}", join(&metadata.optional));
+        println!("provides: {}", join(&metadata.provides));
+    }
+    if let Some(package) = installed {
+        println!("depends: {}", join(&package.depends));
+        if !package.options.is_empty() {
+            println!("options:");
+            for (key, value) in &package.options {
+                println!("  {key} = {value}");
+            }
+        }
+        println!("files:");
+        for path in package.files.keys() {
+            println!("  {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+pub fn search(state: &schema::State<()>, term: &str) -> anyhow::Result<()> {
+    let term = term.to_lowercase();
+    print(state, |package_name, metadata| {
+        package_name.to_lowercase().contains(&term)
+            || metadata.is_some_and(|metadata| {
+                metadata.description.to_lowercase().contains(&term)
+                    || metadata
+                        .requires
+                        .iter()
+                        .chain(&metadata.optional)
+                        .chain(&metadata.provides)
+                        .any(|name| name.to_lowercase().contains(&term))
+            })
+    })
+}
+
+fn print<F>(state: &schema::State<()>, filter: F) -> anyhow::Result<()>
+where
+    F: Fn(&str, Option<&schema::Metadata>) -> bool,
+{
+    let mut rows = BTreeMap::<_, (_, _)>::new();
+    for (package_name, entry) in packages::packages()? {
+        rows.entry(package_name).or_default().0 = Some(entry.metadata);
+    }
+    for package in &state.packages {
+        rows.entry(package.name.clone()).or_default().1 = true;
+    }
+    rows.retain(|package_name, (metadata, _)| filter(package_name, metadata.as_ref()));
+
+    let width = rows.keys().map(String::len).max().unwrap_or_default();
+    for (package_name, (metadata, installed)) in &rows {
+        println!(
+            "{package_name:width$}  {:9}  {}",
+            status(metadata.is_some(), *installed),
+            metadata
+                .as_ref()
+                .map(|metadata| metadata.description.as_str())
+                .unwrap_or_default(),
+        );
+    }
+    Ok(())
+}
+
+fn status(available: bool, installed: bool) -> &'static str {
+    match (available, installed) {
+        (true, true) => "installed",
+        (false, true) => "orphaned",
+        _ => "available",
+    }
+}
+
+fn join<'a, I>(names: I) -> String
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    names
+        .into_iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
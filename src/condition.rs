@@ -0,0 +1,69 @@
+use crate::{packages, schema, system};
+use std::env;
+use std::os::unix::fs::PermissionsExt;
+
+pub fn unmet(conditions: &[schema::Condition]) -> anyhow::Result<Vec<schema::Condition>> {
+    let mut unmet = Vec::new();
+    for condition in conditions {
+        if !check(condition)? {
+            unmet.push(condition.clone());
+        }
+    }
+    Ok(unmet)
+}
+
+fn check(condition: &schema::Condition) -> anyhow::Result<bool> {
+    match condition {
+        schema::Condition::Exists(path) => Ok(packages::home(path)?.try_exists()?),
+        schema::Condition::Command(name) => Ok(env::var_os("PATH").is_some_and(|paths| {
+            env::split_paths(&paths).any(|path| {
+                path.join(name).metadata().is_ok_and(|metadata| {
+                    metadata.is_file() && metadata.permissions().mode() & 0o111 != 0
+                })
+            })
+        })),
+        schema::Condition::Installed(name) => Ok(system::installed(name)?),
+        schema::Condition::Env(name) => Ok(env::var_os(name).is_some()),
+        schema::Condition::Hostname(hostname) => {
+            Ok(nix::unistd::gethostname()?.to_string_lossy() == hostname.as_str())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unmet_conditions() {
+        let conditions = [
+            schema::Condition::Env("PATH".to_owned()),
+            schema::Condition::Env("AKABEI_TEST_UNSET".to_owned()),
+            schema::Condition::Command("sh".to_owned()),
+            schema::Condition::Command("akabei-test-missing".to_owned()),
+            schema::Condition::Exists("/".into()),
+            schema::Condition::Exists("/akabei-test-missing".into()),
+        ];
+        let unmet = unmet(&conditions)
+            .unwrap()
+            .iter()
+            .map(|condition| format!("{condition:?}"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            unmet,
+            [
+                r#"Env("AKABEI_TEST_UNSET")"#,
+                r#"Command("akabei-test-missing")"#,
+                r#"Exists("/akabei-test-missing")"#,
+            ],
+        );
+    }
+
+    #[test]
+    fn hostname() {
+        let hostname = nix::unistd::gethostname().unwrap();
+        let hostname = hostname.to_string_lossy().into_owned();
+        assert!(check(&schema::Condition::Hostname(hostname.clone())).unwrap());
+        assert!(!check(&schema::Condition::Hostname(format!("{hostname}-other"))).unwrap());
+    }
+}
@@ -0,0 +1,449 @@
+use crate::{packages, secret, template};
+use askama::filters::Safe;
+use serde::{Serialize, Serializer};
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::Index;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Context {
+    pub env: Map<String>,
+    pub home: Input<String>,
+    pub user: Input<String>,
+    pub uid: Input<u32>,
+    pub gid: Input<u32>,
+    pub hostname: Input<String>,
+    pub xdg: Xdg,
+    pub user_dirs: UserDirs,
+    pub os: Map<String>,
+    pub package: Input<String>,
+    pub options: Map<Value>,
+    pub theme: Theme,
+    pub fonts: Fonts,
+    #[serde(skip)]
+    pub files: template::Files,
+    #[serde(skip)]
+    pub recorder: Recorder,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Xdg {
+    pub config_home: Input<String>,
+    pub data_home: Input<String>,
+    pub cache_home: Input<String>,
+    pub state_home: Input<String>,
+    pub runtime_dir: Input<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct UserDirs {
+    pub desktop: Input<String>,
+    pub documents: Input<String>,
+    pub download: Input<String>,
+    pub music: Input<String>,
+    pub pictures: Input<String>,
+    pub public_share: Input<String>,
+    pub templates: Input<String>,
+    pub videos: Input<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[allow(non_snake_case)]
+pub struct Theme {
+    pub name: Input<String>,
+    pub base00: Input<String>,
+    pub base01: Input<String>,
+    pub base02: Input<String>,
+    pub base03: Input<String>,
+    pub base04: Input<String>,
+    pub base05: Input<String>,
+    pub base06: Input<String>,
+    pub base07: Input<String>,
+    pub base08: Input<String>,
+    pub base09: Input<String>,
+    pub base0A: Input<String>,
+    pub base0B: Input<String>,
+    pub base0C: Input<String>,
+    pub base0D: Input<String>,
+    pub base0E: Input<String>,
+    pub base0F: Input<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Fonts {
+    pub mono: Input<String>,
+    pub sans: Input<String>,
+    pub emoji: Input<String>,
+    pub cjk: Input<String>,
+    pub size: Input<u32>,
+    pub bar: Input<String>,
+    pub bar_size: Input<u32>,
+}
+
+// strings are rendered without quotes
+#[derive(Clone, Debug)]
+pub struct Value(toml::Value);
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            toml::Value::String(value) => value.fmt(f),
+            value => value.fmt(f),
+        }
+    }
+}
+
+impl Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.0.serialize(serializer)
+    }
+}
+
+// collects the inputs a template consumed, and the first error it ran into,
+// since a compiled template only sees `fmt::Error`
+#[derive(Clone, Debug, Default)]
+pub struct Recorder {
+    pins: Arc<BTreeMap<String, String>>,
+    state: Arc<Mutex<(template::Inputs, Option<anyhow::Error>)>>,
+}
+
+impl Recorder {
+    fn input<T>(&self, key: String, value: T) -> Input<T> {
+        Input {
+            pin: self.pins.get(&key).cloned(),
+            key,
+            value: Some(value),
+            recorder: self.clone(),
+        }
+    }
+
+    // a pinned key is defined even if it is missing on this host
+    fn map<T, I>(&self, prefix: &str, entries: I) -> Map<T>
+    where
+        I: IntoIterator<Item = (String, T)>,
+    {
+        let mut entries = entries
+            .into_iter()
+            .map(|(key, value)| (key.clone(), self.input(format!("{prefix}.{key}"), value)))
+            .collect::<BTreeMap<_, _>>();
+        for (key, value) in self.pins.iter() {
+            if let Some(key) = key
+                .strip_prefix(prefix)
+                .and_then(|key| key.strip_prefix('.'))
+                && !entries.contains_key(key)
+            {
+                entries.insert(
+                    key.to_owned(),
+                    Input {
+                        key: format!("{prefix}.{key}"),
+                        value: None,
+                        pin: Some(value.clone()),
+                        recorder: self.clone(),
+                    },
+                );
+            }
+        }
+        Map {
+            entries,
+            missing: Input {
+                key: prefix.to_owned(),
+                value: None,
+                pin: None,
+                recorder: self.clone(),
+            },
+        }
+    }
+
+    pub fn pins(&self) -> &BTreeMap<String, String> {
+        &self.pins
+    }
+
+    pub fn record(&self, key: String, value: String) {
+        self.state.lock().unwrap().0.insert(key, value);
+    }
+
+    pub fn fail(&self, e: anyhow::Error) {
+        self.state.lock().unwrap().1.get_or_insert(e);
+    }
+
+    pub fn error(&self) -> Option<anyhow::Error> {
+        self.state.lock().unwrap().1.take()
+    }
+
+    pub fn finish(&self) -> anyhow::Result<template::Inputs> {
+        match self.error() {
+            Some(e) => Err(e),
+            None => Ok(self.state.lock().unwrap().0.clone()),
+        }
+    }
+}
+
+// a leaf of the context, recorded as an input once a template prints it
+#[derive(Clone, Debug)]
+pub struct Input<T> {
+    key: String,
+    value: Option<T>,
+    pin: Option<String>,
+    recorder: Recorder,
+}
+
+impl<T> fmt::Display for Input<T>
+where
+    T: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = match (&self.pin, &self.value) {
+            (Some(pin), _) => pin.clone(),
+            (None, Some(value)) => value.to_string(),
+            (None, None) => {
+                self.recorder
+                    .fail(anyhow::format_err!("undefined value `{}`", self.key));
+                return Err(fmt::Error);
+            }
+        };
+        f.write_str(&value)?;
+        self.recorder.record(self.key.clone(), value);
+        Ok(())
+    }
+}
+
+impl<T> Serialize for Input<T>
+where
+    T: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match (&self.pin, &self.value) {
+            (Some(pin), _) => pin.serialize(serializer),
+            (None, value) => value.serialize(serializer),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Map<T> {
+    entries: BTreeMap<String, Input<T>>,
+    missing: Input<T>,
+}
+
+impl<T> Index<&str> for Map<T> {
+    type Output = Input<T>;
+    fn index(&self, key: &str) -> &Self::Output {
+        self.entries.get(key).unwrap_or_else(|| {
+            self.missing.recorder.fail(anyhow::format_err!(
+                "undefined value `{}.{key}`",
+                self.missing.key,
+            ));
+            &self.missing
+        })
+    }
+}
+
+impl<T> Serialize for Map<T>
+where
+    T: Serialize,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        self.entries.serialize(serializer)
+    }
+}
+
+impl Context {
+    pub fn new(package: &packages::Package) -> anyhow::Result<Self> {
+        let recorder = Recorder {
+            pins: Arc::new(package.pins.clone()),
+            state: Arc::default(),
+        };
+        let input = |key: &str, value: String| recorder.input(key.to_owned(), value);
+        let home = dirs::home_dir().ok_or_else(|| anyhow::format_err!("missing home_dir"))?;
+        let uid = nix::unistd::getuid();
+        let dir = |key: &str, dir: Option<PathBuf>, fallback: &str| {
+            input(
+                key,
+                dir.unwrap_or_else(|| home.join(fallback))
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+        };
+        let font = |key: &str, font: &Option<String>, fallback: &str| {
+            input(key, font.clone().unwrap_or_else(|| fallback.to_owned()))
+        };
+        let size = package.fonts.size.unwrap_or(16);
+        let color = |key: &str| {
+            input(
+                &format!("theme.{key}"),
+                package.theme.palette.get(key).cloned().unwrap_or_default(),
+            )
+        };
+        Ok(Self {
+            env: recorder.map("env", std::env::vars()),
+            home: input("home", home.to_string_lossy().into_owned()),
+            user: input(
+                "user",
+                nix::unistd::User::from_uid(uid)?
+                    .map(|user| user.name)
+                    .or_else(|| std::env::var("USER").ok())
+                    .unwrap_or_default(),
+            ),
+            uid: recorder.input("uid".to_owned(), uid.as_raw()),
+            gid: recorder.input("gid".to_owned(), nix::unistd::getgid().as_raw()),
+            hostname: input(
+                "hostname",
+                nix::unistd::gethostname()?.to_string_lossy().into_owned(),
+            ),
+            xdg: Xdg {
+                config_home: dir("xdg.config_home", dirs::config_dir(), ".config"),
+                data_home: dir("xdg.data_home", dirs::data_dir(), ".local/share"),
+                cache_home: dir("xdg.cache_home", dirs::cache_dir(), ".cache"),
+                state_home: dir("xdg.state_home", dirs::state_dir(), ".local/state"),
+                runtime_dir: input(
+                    "xdg.runtime_dir",
+                    dirs::runtime_dir()
+                        .unwrap_or_else(|| Path::new("/run/user").join(uid.to_string()))
+                        .to_string_lossy()
+                        .into_owned(),
+                ),
+            },
+            user_dirs: UserDirs {
+                desktop: dir("user_dirs.desktop", dirs::desktop_dir(), "Desktop"),
+                documents: dir("user_dirs.documents", dirs::document_dir(), "Documents"),
+                download: dir("user_dirs.download", dirs::download_dir(), "Downloads"),
+                music: dir("user_dirs.music", dirs::audio_dir(), "Music"),
+                pictures: dir("user_dirs.pictures", dirs::picture_dir(), "Pictures"),
+                public_share: dir("user_dirs.public_share", dirs::public_dir(), "Public"),
+                templates: dir("user_dirs.templates", dirs::template_dir(), "Templates"),
+                videos: dir("user_dirs.videos", dirs::video_dir(), "Videos"),
+            },
+            os: recorder.map("os", os_release()?),
+            package: input("package", package.name.clone()),
+            options: recorder.map(
+                "options",
+                package
+                    .declared
+                    .iter()
+                    .map(|(name, value)| (name.clone(), Value(value.clone()))),
+            ),
+            theme: Theme {
+                name: input("theme.name", package.theme.name.clone()),
+                base00: color("base00"),
+                base01: color("base01"),
+                base02: color("base02"),
+                base03: color("base03"),
+                base04: color("base04"),
+                base05: color("base05"),
+                base06: color("base06"),
+                base07: color("base07"),
+                base08: color("base08"),
+                base09: color("base09"),
+                base0A: color("base0A"),
+                base0B: color("base0B"),
+                base0C: color("base0C"),
+                base0D: color("base0D"),
+                base0E: color("base0E"),
+                base0F: color("base0F"),
+            },
+            fonts: Fonts {
+                mono: font("fonts.mono", &package.fonts.mono, "Iosevka Nerd Font Mono"),
+                sans: font("fonts.sans", &package.fonts.sans, "Noto Sans"),
+                emoji: font("fonts.emoji", &package.fonts.emoji, "Noto Color Emoji"),
+                cjk: font("fonts.cjk", &package.fonts.cjk, "Noto Sans CJK JP"),
+                size: recorder.input("fonts.size".to_owned(), size),
+                // window titles and bars keep their own font unless one is configured,
+                // and are set smaller than text
+                bar: font("fonts.bar", &package.fonts.mono, "Noto Sans Mono"),
+                bar_size: recorder.input("fonts.bar_size".to_owned(), size * 3 / 4),
+            },
+            files: package.loaded.clone(),
+            recorder,
+        })
+    }
+
+    pub fn package_file(&self, package: &str, path: &str) -> Safe<String> {
+        Safe(self.try_package_file(package, path).unwrap_or_else(|e| {
+            self.recorder.fail(e);
+            String::new()
+        }))
+    }
+
+    // raises `Pending` if the package is not loaded yet
+    fn try_package_file(&self, package: &str, path: &str) -> anyhow::Result<String> {
+        let files = self.files.lock().unwrap();
+        let files = files
+            .get(package)
+            .ok_or_else(|| template::Pending(package.to_owned()))?;
+        let content = packages::home(path)
+            .ok()
+            .and_then(|path| files.get(&path))
+            .ok_or_else(|| anyhow::format_err!("`{path}` is not a file of `{package}`"))?;
+        self.recorder.record(
+            format!("package_file.{package}:{path}"),
+            hex::encode(Sha1::digest(content)),
+        );
+        Ok(String::from_utf8_lossy(content).into_owned())
+    }
+
+    pub fn secret(&self, name: &str) -> String {
+        self.try_secret(name).unwrap_or_else(|e| {
+            self.recorder.fail(e);
+            String::new()
+        })
+    }
+
+    // only the hash of a secret is recorded, so that the state never contains its value
+    fn try_secret(&self, name: &str) -> anyhow::Result<String> {
+        let key = format!("secret.{name}");
+        match secret::get(name)? {
+            Some(value) => {
+                self.recorder.record(key, secret::hash(&value)?);
+                Ok(value)
+            }
+            None => {
+                tracing::warn!(secret.name = name, "not resolved in a dry run");
+                self.recorder.record(key, template::UNRESOLVED.to_owned());
+                Ok(template::UNRESOLVED.to_owned())
+            }
+        }
+    }
+}
+
+fn os_release() -> anyhow::Result<BTreeMap<String, String>> {
+    for path in ["/etc/os-release", "/usr/lib/os-release"] {
+        let s = match fs::read_to_string(path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        return Ok(s
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| {
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .or_else(|| {
+                        value
+                            .strip_prefix('\'')
+                            .and_then(|value| value.strip_suffix('\''))
+                    })
+                    .unwrap_or(value);
+                (key.trim().to_owned(), value.to_owned())
+            })
+            .collect());
+    }
+    Ok(BTreeMap::new())
+}
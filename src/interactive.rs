@@ -0,0 +1,269 @@
+use crate::{Diff, misc, schema, secret};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::Infallible;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default)]
+pub struct Skipped<'a> {
+    pub packages: BTreeSet<&'a str>,
+    pub paths: BTreeSet<&'a Path>,
+}
+
+enum Answer {
+    Accept,
+    Skip,
+    View,
+}
+
+fn ask(prompt: fmt::Arguments) -> io::Result<Answer> {
+    loop {
+        match misc::ask(format_args!("{prompt} [y,n,v]"))?.as_deref() {
+            Some("y" | "yes") => break Ok(Answer::Accept),
+            None | Some("n" | "no") => break Ok(Answer::Skip),
+            Some("v" | "view") => break Ok(Answer::View),
+            Some(_) => (),
+        }
+    }
+}
+
+type Change<'a, T, C> = (Option<&'a schema::File<T>>, Option<&'a schema::File<C>>);
+
+pub fn review<'a, T, C>(
+    diff: &[Diff<'a, T, C>],
+    orphan: &BTreeSet<&'a Path>,
+    skipped: &mut Skipped<'a>,
+) -> anyhow::Result<()>
+where
+    C: AsRef<[u8]>,
+{
+    // an orphan is removed to make way for the file installed in its place
+    let label = |path: &Path, change: &Change<'_, T, C>| {
+        if orphan.contains(path) {
+            Some("overwrite orphan")
+        } else {
+            kind(change)
+        }
+    };
+    let mut reviewed = BTreeSet::new();
+    'package: for (span, before, after) in diff {
+        let package_name = crate::package_name(before, after);
+        if skipped.packages.contains(package_name) {
+            continue;
+        }
+
+        let mut changes = BTreeMap::<_, Change<_, _>>::new();
+        for (path, file) in before.iter().flat_map(|package| &package.files) {
+            changes.entry(path.as_path()).or_default().0 = Some(file);
+        }
+        for (path, file) in after.iter().flat_map(|package| &package.files) {
+            changes.entry(path.as_path()).or_default().1 = Some(file);
+        }
+
+        let action = span.metadata().map_or("change", |metadata| metadata.name());
+        loop {
+            match ask(format_args!("{action} package `{package_name}`?"))? {
+                Answer::Accept => break,
+                Answer::Skip => {
+                    skipped.skip(before, after);
+                    continue 'package;
+                }
+                Answer::View => {
+                    for (path, change) in &changes {
+                        if let Some(kind) = label(path, change) {
+                            eprintln!("  {kind} {}", path.display());
+                        }
+                    }
+                }
+            }
+        }
+
+        for (path, change) in changes {
+            let Some(kind) = label(path, &change) else {
+                continue;
+            };
+            reviewed.insert(path);
+            loop {
+                match ask(format_args!("{kind} {}?", path.display()))? {
+                    Answer::Accept => break,
+                    Answer::Skip => {
+                        skipped.paths.insert(path);
+                        break;
+                    }
+                    Answer::View => view(path, &change)?,
+                }
+            }
+        }
+    }
+
+    for path in orphan {
+        if skipped.paths.contains(path) || reviewed.contains(path) {
+            continue;
+        }
+        loop {
+            match ask(format_args!("remove orphan {}?", path.display()))? {
+                Answer::Accept => break,
+                Answer::Skip => {
+                    skipped.paths.insert(path);
+                    break;
+                }
+                Answer::View => view::<T, C>(path, &(None, None))?,
+            }
+        }
+    }
+    Ok(())
+}
+
+fn kind<T, C>(change: &Change<'_, T, C>) -> Option<&'static str> {
+    match change {
+        (Some(before), Some(after)) => {
+            (before.sha1 != after.sha1 || before.mode != after.mode).then_some("update")
+        }
+        (Some(_), None) => Some("remove"),
+        (None, Some(_)) => Some("install"),
+        (None, None) => None,
+    }
+}
+
+fn view<T, C>(path: &Path, change: &Change<'_, T, C>) -> io::Result<()>
+where
+    C: AsRef<[u8]>,
+{
+    let (before, after) = change;
+    if let (Some(before), Some(after)) = (before, after)
+        && before.mode != after.mode
+    {
+        eprintln!("mode {:o} -> {:o}", before.mode, after.mode);
+    }
+    // an orphan is shown as it is on disk as well
+    let old = match fs::read(path) {
+        Ok(old) => old,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    let new = after.map_or(&[][..], |after| after.extra.as_ref());
+    let path = path.display().to_string();
+    // the previous content may hold a value of a secret that has changed since
+    if !before.iter().all(|before| secret::known(&before.inputs))
+        || !after.iter().all(|after| secret::known(&after.inputs))
+    {
+        eprintln!("diff of {path} is hidden since it contains secrets");
+        return Ok(());
+    }
+    eprint!(
+        "{}",
+        similar::TextDiff::from_lines(
+            &*secret::redact(&String::from_utf8_lossy(&old)),
+            &*secret::redact(&String::from_utf8_lossy(new))
+        )
+        .unified_diff()
+        .header(&path, &path),
+    );
+    Ok(())
+}
+
+pub fn hook(command: &[String]) -> io::Result<bool> {
+    misc::confirm(format_args!("run {command:?}?"))
+}
+
+impl<'a> Skipped<'a> {
+    pub fn only<T, C>(diff: &[Diff<'a, T, C>], only: &BTreeSet<String>) -> Self {
+        for package_name in only {
+            if !diff
+                .iter()
+                .any(|(_, before, after)| crate::package_name(before, after) == package_name)
+            {
+                tracing::warn!(package.name = package_name, "no pending changes");
+            }
+        }
+
+        let mut skipped = Self::default();
+        if !only.is_empty() {
+            for (_, before, after) in diff {
+                if !only.contains(crate::package_name(before, after)) {
+                    skipped.skip(before, after);
+                }
+            }
+        }
+        skipped
+    }
+
+    pub fn keep<T, C>(&mut self, diff: &[Diff<'a, T, C>], package_names: &BTreeSet<&str>) {
+        for (_, before, after) in diff {
+            if package_names.contains(crate::package_name(before, after)) {
+                self.skip(before, after);
+            }
+        }
+    }
+
+    fn skip<T, C>(
+        &mut self,
+        before: &Option<&'a schema::Package<T>>,
+        after: &Option<&'a schema::Package<C>>,
+    ) {
+        self.packages.insert(crate::package_name(before, after));
+        self.paths.extend(
+            before
+                .iter()
+                .flat_map(|package| package.files.keys())
+                .chain(after.iter().flat_map(|package| package.files.keys()))
+                .map(PathBuf::as_path),
+        );
+    }
+
+    pub fn state(
+        &self,
+        before: &schema::State<()>,
+        after: &schema::State<Vec<u8>>,
+    ) -> schema::State<()> {
+        let Ok(after) = after.try_map(|_, _| Ok::<_, Infallible>(()));
+        let mut packages = after
+            .packages
+            .into_iter()
+            .map(|package| (package.name.clone(), package))
+            .collect::<BTreeMap<_, _>>();
+        for package in packages.values_mut() {
+            package
+                .files
+                .retain(|path, _| !self.paths.contains(path.as_path()));
+        }
+        for package in &before.packages {
+            if self.packages.contains(package.name.as_str()) {
+                packages.insert(package.name.clone(), package.clone());
+                continue;
+            }
+            for (path, file) in &package.files {
+                if self.paths.contains(path.as_path()) {
+                    packages
+                        .entry(package.name.clone())
+                        .or_insert_with(|| schema::Package {
+                            name: package.name.clone(),
+                            files: BTreeMap::new(),
+                            hooks: package.hooks.clone(),
+                            options: package.options.clone(),
+                            depends: package.depends.clone(),
+                            aggregates: package.aggregates.clone(),
+                            fragments: package.fragments.clone(),
+                            dropins: package.dropins.clone(),
+                        })
+                        .files
+                        .insert(path.clone(), file.clone());
+                }
+            }
+        }
+        for package_name in &self.packages {
+            if !before
+                .packages
+                .iter()
+                .any(|package| package.name == *package_name)
+            {
+                packages.remove(*package_name);
+            }
+        }
+        schema::State {
+            packages: packages.into_values().collect(),
+        }
+    }
+}
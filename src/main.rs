@@ -1,73 +1,352 @@
+mod aggregate;
+mod catalog;
+mod condition;
+mod context;
+mod interactive;
+mod manifest;
 mod misc;
 mod packages;
+mod plan;
+mod repair;
 mod schema;
+mod secret;
+mod selection;
+mod system;
+mod template;
+mod theme;
 
-use clap::Parser;
+use clap::{CommandFactory, Parser};
+use clap_complete::{ArgValueCandidates, CompletionCandidate};
 use std::collections::{BTreeMap, BTreeSet};
 use std::env;
 use std::fmt;
-use std::fs::{self, File};
+use std::fs::{self, File, OpenOptions, Permissions};
 use std::mem;
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(Parser)]
 struct Args {
-    #[clap(long, num_args = 1..)]
+    #[clap(subcommand)]
+    command: Option<Command>,
+    #[clap(long, num_args = 1.., global = true, add = ArgValueCandidates::new(available))]
     install: Vec<String>,
-    #[clap(long, num_args = 1..)]
+    #[clap(long, num_args = 1.., global = true, add = ArgValueCandidates::new(installed))]
     remove: Vec<String>,
+    #[clap(long, num_args = 1.., global = true, add = ArgValueCandidates::new(installed))]
+    reinstall: Vec<String>,
+    #[clap(long, num_args = 1.., global = true, add = ArgValueCandidates::new(installed))]
+    only: Vec<String>,
     #[clap(long)]
     apply: bool,
+    #[clap(long, global = true)]
+    interactive: bool,
+    #[clap(long, global = true)]
+    profile: Option<String>,
+    #[clap(long, global = true)]
+    cascade: bool,
+    #[clap(long, global = true)]
+    with_system_deps: bool,
+    #[clap(long, global = true)]
+    keep_going: bool,
+    #[clap(long)]
+    check_templates: bool,
+    #[clap(long, num_args = 1.., value_name = "PACKAGE.KEY=VALUE", global = true)]
+    set: Vec<String>,
+    #[clap(long, num_args = 1.., value_name = "KEY[=VALUE]", global = true)]
+    pin: Vec<String>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    Repair {
+        targets: Vec<String>,
+        #[clap(long)]
+        confirm: bool,
+        #[clap(long)]
+        hooks: bool,
+        #[clap(long)]
+        apply: bool,
+    },
+    Plan {
+        #[clap(long)]
+        out: PathBuf,
+    },
+    Apply {
+        plan: PathBuf,
+    },
+    Init {
+        #[clap(long)]
+        force: bool,
+    },
+    List,
+    Info {
+        #[clap(add = ArgValueCandidates::new(available_or_installed))]
+        package: String,
+    },
+    Search {
+        term: String,
+    },
 }
 
 fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+    clap_complete::CompleteEnv::with_factory(Args::command).complete();
+    tracing_subscriber::fmt()
+        .with_writer(|| secret::Redacted)
+        .init();
 
     let args = Args::parse();
+    // the other subcommands would silently ignore changes to the selection
+    if !matches!(args.command, None | Some(Command::Plan { .. })) {
+        for (flag, given) in [
+            ("--install", !args.install.is_empty()),
+            ("--remove", !args.remove.is_empty()),
+            ("--reinstall", !args.reinstall.is_empty()),
+            ("--only", !args.only.is_empty()),
+            ("--set", !args.set.is_empty()),
+            ("--cascade", args.cascade),
+            ("--pin", !args.pin.is_empty()),
+        ] {
+            anyhow::ensure!(!given, "`{flag}` only applies when planning changes");
+        }
+    }
+    secret::resolve_commands(
+        args.apply
+            || matches!(
+                args.command,
+                Some(
+                    Command::Plan { .. }
+                        | Command::Apply { .. }
+                        | Command::Repair { apply: true, .. }
+                )
+            ),
+    );
 
-    let data_path = dirs::data_dir()
-        .ok_or_else(|| anyhow::format_err!("missing data_dir"))?
-        .join(concat!(env!("CARGO_BIN_NAME"), ".json"));
+    let data_path = data_path()?;
+    let mut before = read_state(&data_path)?;
+    let selection_path = selection::path()?;
+    let mut selection = selection::read(&selection_path)?;
+
+    if let Some(Command::Init { force }) = &args.command {
+        anyhow::ensure!(
+            selection.is_none() || *force,
+            "`{}` already exists",
+            selection_path.display(),
+        );
+        let mut selection = schema::Selection::default();
+        for package in before.packages {
+            if !package.options.is_empty() {
+                selection
+                    .options
+                    .insert(package.name.clone(), package.options);
+            }
+            selection.packages.insert(package.name);
+        }
+        return selection::write(&selection_path, &selection);
+    }
+
+    match &args.command {
+        Some(Command::List) => return catalog::list(&before),
+        Some(Command::Info { package }) => return catalog::info(&before, package),
+        Some(Command::Search { term }) => return catalog::search(&before, term),
+        _ => (),
+    }
 
-    let mut before = if data_path.try_exists()? {
-        serde_json::from_reader(File::open(&data_path)?)?
+    if let Some(Command::Repair {
+        targets,
+        confirm,
+        hooks,
+        apply,
+    }) = &args.command
+    {
+        let result = repair::repair(
+            &mut before,
+            targets,
+            selection.as_ref(),
+            args.profile.as_deref(),
+            *confirm,
+            *hooks,
+            *apply,
+        );
+        // files repaired before a failure are recorded as well
+        if *apply {
+            fs::write(&data_path, serde_json::to_vec_pretty(&before)?)?;
+        }
+        return result;
+    }
+
+    if let Some(Command::Apply { plan }) = &args.command {
+        let (plan, after) = plan::load(plan, &data_path)?;
+        let orphan = plan.orphan.iter().map(AsRef::as_ref).collect();
+        let diff = diff(&plan.before, &after, &plan.reinstall);
+        let mut skipped = interactive::Skipped::only(&diff, &plan.only);
+        skipped.keep(&diff, &kept(&plan.unmet, &plan.failed));
+        if args.interactive {
+            interactive::review(&diff, &orphan, &mut skipped)?;
+        }
+        if args.with_system_deps {
+            system::install(&plan.missing, true)?;
+        }
+        action(&diff, &orphan, true, args.interactive, &skipped)?;
+        let state = skipped.state(&plan.before, &after);
+        fs::write(&data_path, serde_json::to_vec_pretty(&state)?)?;
+        if let Some(selection) = &plan.selection {
+            selection::update(&selection_path, selection)?;
+        }
+        return ensure_loaded(&plan.failed);
+    }
+
+    let set = parse_set(&args.set)?;
+    let mut pins = parse_pin(&args.pin, &before)?;
+    // pins are kept in the selection file, otherwise the next run would silently drop them
+    if let Some(selection) = &mut selection {
+        selection.pins.extend(pins.clone());
+        pins = selection.pins.clone();
     } else {
-        schema::State::<()>::default()
-    };
+        anyhow::ensure!(
+            pins.is_empty(),
+            "`--pin` requires `{}` to keep the pins in",
+            selection_path.display(),
+        );
+    }
+    let (mut after, requires, unmet, failed) = {
+        let mut package_names = BTreeSet::new();
+        let mut profile = None;
+        if let Some(selection) = &mut selection {
+            let profile_name = selection::profile(selection, args.profile.as_deref())?
+                .map(|(profile_name, _)| profile_name.clone());
+            tracing::info!(profile.name = profile_name);
+            profile = profile_name.and_then(|profile_name| {
+                let profile = selection.profiles.remove(&profile_name)?;
+                Some((profile_name, profile))
+            });
 
-    let after = {
-        let mut package_names = before
-            .packages
-            .iter()
-            .map(|package| &package.name)
-            .collect::<BTreeSet<_>>();
-        for package_name in &args.install {
-            package_names.insert(package_name);
+            let selected = profile
+                .as_mut()
+                .map_or(&mut selection.packages, |(_, profile)| {
+                    &mut profile.packages
+                });
+            for package_name in args.install.iter().chain(&args.reinstall) {
+                selected.insert(package_name.clone());
+            }
+            let options = profile
+                .as_mut()
+                .map_or(&mut selection.options, |(_, profile)| &mut profile.options);
+            for (package_name, set) in &set {
+                options
+                    .entry(package_name.clone())
+                    .or_default()
+                    .extend(set.clone());
+            }
+            package_names.extend(selection.packages.iter().cloned());
+            package_names.extend(
+                profile
+                    .iter()
+                    .flat_map(|(_, profile)| profile.packages.iter().cloned()),
+            );
+        } else {
+            package_names.extend(before.packages.iter().map(|package| package.name.clone()));
+            package_names.extend(args.install.iter().chain(&args.reinstall).cloned());
         }
-        for package_name in &args.remove {
-            package_names.remove(package_name);
+        // a template of an unselected package is checked as well, with its default options
+        if args.check_templates {
+            package_names.extend(packages::packages()?.into_keys());
         }
-        tracing::info!("packages[].name" = ?package_names);
+        let options = |package_name: &str| {
+            let mut options = if let Some(selection) = &selection {
+                selection::options(
+                    selection,
+                    profile.as_ref().map(|(_, profile)| profile),
+                    package_name,
+                )
+            } else {
+                before
+                    .packages
+                    .iter()
+                    .find(|package| package.name == package_name)
+                    .map(|package| package.options.clone())
+                    .unwrap_or_default()
+            };
+            if let Some(set) = set.get(package_name) {
+                options.extend(set.clone());
+            }
+            options
+        };
 
-        let mut packages = packages::packages();
-        let packages = package_names
-            .into_iter()
-            .map(|package_name| {
-                let load = packages
-                    .remove(package_name.as_str())
-                    .ok_or_else(|| anyhow::format_err!("missing package `{package_name}`"))?;
-                let mut package = schema::Package {
-                    name: package_name.clone(),
-                    files: BTreeMap::new(),
-                    hooks: schema::Hooks::default(),
-                };
-                load(&mut package).map(|_| package)
-            })
-            .collect::<Result<_, _>>()?;
-        schema::State { packages }
+        let (theme, fonts) = appearance(
+            selection.as_ref(),
+            profile.as_ref().map(|(_, profile)| profile),
+        )?;
+
+        let mut removed = args.remove.iter().cloned().collect::<BTreeSet<_>>();
+        let (after, requires, unmet, failed) = loop {
+            let (after, requires, unmet, failed) = load(
+                package_names.difference(&removed).cloned(),
+                &before,
+                options,
+                &pins,
+                &theme,
+                &fonts,
+                args.keep_going || args.check_templates,
+            )?;
+            let mut dependents = BTreeSet::new();
+            for package in &after.packages {
+                for dependency in &package.depends {
+                    if removed.contains(dependency) {
+                        anyhow::ensure!(
+                            args.cascade,
+                            "package `{dependency}` is required by `{}`",
+                            package.name,
+                        );
+                        tracing::warn!(package.name, dependency, "cascade");
+                        dependents.insert(package.name.clone());
+                    }
+                }
+            }
+            if dependents.is_empty() {
+                break (after, requires, unmet, failed);
+            }
+            removed.extend(dependents);
+        };
+        tracing::info!("packages[].name" = ?after.packages.iter().map(|package| &package.name).collect::<Vec<_>>());
+        for package_name in set.keys() {
+            anyhow::ensure!(
+                after
+                    .packages
+                    .iter()
+                    .any(|package| package.name == *package_name),
+                "package `{package_name}` is not installed",
+            );
+        }
+
+        if let Some(selection) = &mut selection {
+            for package_name in &removed {
+                selection.packages.remove(package_name);
+                if let Some((_, profile)) = &mut profile {
+                    profile.packages.remove(package_name);
+                }
+            }
+            if let Some((profile_name, profile)) = profile {
+                selection.profiles.insert(profile_name, profile);
+            }
+        }
+        (after, requires, unmet, failed)
     };
 
+    if args.check_templates {
+        let files = after
+            .packages
+            .iter()
+            .map(|package| package.files.len())
+            .sum::<usize>();
+        tracing::info!(files, "rendered");
+        return ensure_loaded(&failed);
+    }
+
+    if !args.apply {
+        unresolved(&before, &mut after);
+    }
     let mut orphan = after
         .packages
         .iter()
@@ -76,55 +355,547 @@ fn main() -> anyhow::Result<()> {
         .collect();
     sync(&mut before, &mut orphan)?;
 
-    let diff = {
-        let mut packages = BTreeMap::<_, (_, _)>::new();
-        for package in &before.packages {
-            packages.entry(&package.name).or_default().0 = Some(package);
+    let reinstall = args.reinstall.iter().cloned().collect();
+    let only = args.only.iter().cloned().collect();
+
+    let missing = system::missing(&requires)?;
+
+    if let Some(Command::Plan { out }) = &args.command {
+        let mut plan = plan::new(
+            &data_path,
+            &selection_path,
+            &before,
+            &after,
+            &orphan,
+            &reinstall,
+            &only,
+        )?;
+        plan.selection = selection;
+        plan.missing = missing;
+        plan.unmet = unmet;
+        plan.failed = failed;
+        // the plan carries the contents of files, secrets included,
+        // so it is never readable by others, not even before it is written
+        let secret = plan
+            .after
+            .packages
+            .iter()
+            .flat_map(|package| package.files.values())
+            .any(|file| secret::names(&file.inputs).next().is_some());
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(if secret { 0o600 } else { 0o666 })
+            .open(out)?;
+        if secret {
+            // `mode` only applies to a file created here
+            file.set_permissions(Permissions::from_mode(0o600))?;
         }
-        for package in &after.packages {
-            packages.entry(&package.name).or_default().1 = Some(package);
+        serde_json::to_writer_pretty(file, &plan)?;
+        return ensure_loaded(&plan.failed);
+    }
+
+    let diff = diff(&before, &after, &reinstall);
+    let mut skipped = interactive::Skipped::only(&diff, &only);
+    skipped.keep(&diff, &kept(&unmet, &failed));
+    if args.interactive && args.apply {
+        interactive::review(&diff, &orphan, &mut skipped)?;
+    }
+    if args.with_system_deps {
+        system::install(&missing, args.apply)?;
+    }
+    action(&diff, &orphan, args.apply, args.interactive, &skipped)?;
+
+    if args.apply {
+        let state = skipped.state(&before, &after);
+        fs::write(&data_path, serde_json::to_vec_pretty(&state)?)?;
+        if let Some(selection) = &selection {
+            selection::update(&selection_path, selection)?;
         }
-        packages
-            .into_iter()
-            .filter_map(|(package_name, (before, after))| match (before, after) {
-                (Some(before), Some(after)) => {
-                    let before_files = before
-                        .files
-                        .iter()
-                        .map(|(path, file)| (path, file.sha1, file.mode));
-                    let after_files = after
-                        .files
-                        .iter()
-                        .map(|(path, file)| (path, file.sha1, file.mode));
-                    if before_files.eq(after_files) {
-                        None
+    }
+
+    ensure_loaded(&failed)
+}
+
+// packages that failed to load or whose conditions are unmet stay as they were
+fn kept<'a>(unmet: &'a Unmet, failed: &'a Failed) -> BTreeSet<&'a str> {
+    unmet
+        .iter()
+        .filter(|(_, unmet)| !unmet.package.is_empty())
+        .map(|(package_name, _)| package_name.as_str())
+        .chain(failed.keys().map(String::as_str))
+        .collect()
+}
+
+fn ensure_loaded(failed: &Failed) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        failed.is_empty(),
+        "failed to load {:?}",
+        failed.keys().collect::<Vec<_>>(),
+    );
+    Ok(())
+}
+
+fn parse_set(set: &[String]) -> anyhow::Result<BTreeMap<String, schema::Options>> {
+    let mut options = BTreeMap::<_, schema::Options>::new();
+    for s in set {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::format_err!("invalid option `{s}`"))?;
+        let (package_name, key) = key
+            .split_once('.')
+            .ok_or_else(|| anyhow::format_err!("invalid option `{s}`"))?;
+        let value = toml::from_str::<toml::Table>(&format!("value = {value}"))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+            .unwrap_or_else(|| value.into());
+        options
+            .entry(package_name.to_owned())
+            .or_default()
+            .insert(key.to_owned(), value);
+    }
+    Ok(options)
+}
+
+// the theme and fonts of the active profile, shared by every package
+fn appearance(
+    selection: Option<&schema::Selection>,
+    profile: Option<&schema::Profile>,
+) -> anyhow::Result<(Arc<schema::Theme>, Arc<schema::Fonts>)> {
+    let theme = selection.map_or(theme::DEFAULT, |selection| {
+        selection::theme(selection, profile)
+    });
+    tracing::info!(theme.name = theme);
+    let fonts = selection.map_or_else(Default::default, |selection| {
+        selection::fonts(selection, profile)
+    });
+    Ok((Arc::new(theme::load(theme)?), Arc::new(fonts)))
+}
+
+// without a value, the key is pinned to the value recorded in the state
+fn parse_pin(
+    pin: &[String],
+    state: &schema::State<()>,
+) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut pins = BTreeMap::new();
+    for s in pin {
+        let (key, value) = match s.split_once('=') {
+            Some((key, value)) => (key, value.to_owned()),
+            None => (
+                s.as_str(),
+                state
+                    .packages
+                    .iter()
+                    .flat_map(|package| package.files.values())
+                    .find_map(|file| file.inputs.get(s).cloned())
+                    .ok_or_else(|| anyhow::format_err!("no recorded value of `{s}`"))?,
+            ),
+        };
+        pins.insert(key.to_owned(), value);
+    }
+    Ok(pins)
+}
+
+fn data_path() -> anyhow::Result<PathBuf> {
+    Ok(dirs::data_dir()
+        .ok_or_else(|| anyhow::format_err!("missing data_dir"))?
+        .join(concat!(env!("CARGO_BIN_NAME"), ".json")))
+}
+
+fn read_state(data_path: &Path) -> anyhow::Result<schema::State<()>> {
+    if data_path.try_exists()? {
+        Ok(serde_json::from_reader(File::open(data_path)?)?)
+    } else {
+        Ok(schema::State::default())
+    }
+}
+
+fn installed() -> Vec<CompletionCandidate> {
+    data_path()
+        .and_then(|data_path| read_state(&data_path))
+        .map(|state| {
+            state
+                .packages
+                .into_iter()
+                .map(|package| CompletionCandidate::new(package.name))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn available() -> Vec<CompletionCandidate> {
+    let installed = installed();
+    packages::packages()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(package_name, entry)| {
+            CompletionCandidate::new(package_name).help(Some(entry.metadata.description.into()))
+        })
+        .filter(|candidate| {
+            !installed
+                .iter()
+                .any(|installed| installed.get_value() == candidate.get_value())
+        })
+        .collect()
+}
+
+fn available_or_installed() -> Vec<CompletionCandidate> {
+    let mut candidates = installed();
+    candidates.extend(available());
+    candidates
+}
+
+fn diff<'a, T, C>(
+    before: &'a schema::State<T>,
+    after: &'a schema::State<C>,
+    reinstall: &BTreeSet<String>,
+) -> Vec<Diff<'a, T, C>> {
+    let mut packages = BTreeMap::<_, (_, _)>::new();
+    for package in &before.packages {
+        packages.entry(&package.name).or_default().0 = Some(package);
+    }
+    for package in &after.packages {
+        packages.entry(&package.name).or_default().1 = Some(package);
+    }
+    let mut pending = packages
+        .into_iter()
+        .filter_map(|(package_name, (before, after))| match (before, after) {
+            (Some(before), Some(after)) => {
+                let before_files = before
+                    .files
+                    .iter()
+                    .map(|(path, file)| (path, file.sha1, file.mode));
+                let after_files = after
+                    .files
+                    .iter()
+                    .map(|(path, file)| (path, file.sha1, file.mode));
+                if !before_files.eq(after_files) {
+                    let span = if composed(before, after) {
+                        tracing::info_span!("compose", package.name = package_name)
                     } else {
-                        let span = tracing::info_span!("upgrade", package.name = package_name);
-                        Some((span, Some(before), Some(after)))
-                    }
-                }
-                (Some(before), None) => {
-                    let span = tracing::info_span!("remove", package.name = package_name);
-                    Some((span, Some(before), None))
-                }
-                (None, Some(after)) => {
-                    let span = tracing::info_span!("install", package.name = package_name);
-                    Some((span, None, Some(after)))
+                        tracing::info_span!("upgrade", package.name = package_name)
+                    };
+                    span.in_scope(|| {
+                        for (path, changes) in rerendered(before, after) {
+                            for (input, (from, to)) in changes {
+                                tracing::info!(?path, input, from, to, "re-rendered");
+                            }
+                        }
+                    });
+                    Some((span, Some(before), Some(after)))
+                } else if reinstall.contains(package_name) {
+                    let span = tracing::info_span!("reinstall", package.name = package_name);
+                    Some((span, Some(before), Some(after)))
+                } else {
+                    None
                 }
-                (None, None) => None,
+            }
+            (Some(before), None) => {
+                let span = tracing::info_span!("remove", package.name = package_name);
+                Some((span, Some(before), None))
+            }
+            (None, Some(after)) => {
+                let span = tracing::info_span!("install", package.name = package_name);
+                Some((span, None, Some(after)))
+            }
+            (None, None) => None,
+        })
+        .map(|diff| (package_name(&diff.1, &diff.2), diff))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut sorted = Vec::new();
+    while !pending.is_empty() {
+        let mut ready = pending
+            .iter()
+            .filter(|(_, (_, before, after))| {
+                after
+                    .map(|package| &package.depends)
+                    .or(before.map(|package| &package.depends))
+                    .is_none_or(|depends| {
+                        depends
+                            .iter()
+                            .all(|dependency| !pending.contains_key(dependency.as_str()))
+                    })
             })
-            .collect()
+            .map(|(package_name, _)| *package_name)
+            .collect::<Vec<_>>();
+        if ready.is_empty() {
+            tracing::warn!(packages = ?pending.keys().collect::<Vec<_>>(), "dependency cycle");
+            ready = pending.keys().copied().collect();
+        }
+        for package_name in ready {
+            sorted.extend(pending.remove(package_name));
+        }
+    }
+    sorted
+}
+
+// a package whose only changes are its file aggregates, recomposed as contributors come and go,
+// or its fragments in drop-in directories, is upgraded without running its hooks
+fn composed<T, C>(before: &schema::Package<T>, after: &schema::Package<C>) -> bool {
+    let changed = |path: &PathBuf| match (before.files.get(path), after.files.get(path)) {
+        (Some(before), Some(after)) => before.sha1 != after.sha1 || before.mode != after.mode,
+        _ => true,
     };
+    let aggregated = |path: &PathBuf| {
+        after.aggregates.get(path) == Some(&schema::Aggregate::File)
+            || before.dropins.contains(path)
+            || after.dropins.contains(path)
+    };
+    let mut changes = before
+        .files
+        .keys()
+        .chain(after.files.keys())
+        .filter(|path| changed(path))
+        .peekable();
+    // a package reinstalled as it was runs its hooks
+    before.hooks == after.hooks && changes.peek().is_some() && changes.all(aggregated)
+}
 
-    action(&diff, &orphan, args.apply)?;
+type Requires = BTreeMap<String, BTreeSet<String>>;
+type Unmet = BTreeMap<String, schema::Unmet>;
+type Failed = BTreeMap<String, String>;
 
-    if args.apply {
-        fs::write(&data_path, serde_json::to_vec_pretty(&after)?)?;
+fn load<I, F>(
+    package_names: I,
+    before: &schema::State<()>,
+    options: F,
+    pins: &BTreeMap<String, String>,
+    theme: &Arc<schema::Theme>,
+    fonts: &Arc<schema::Fonts>,
+    keep_going: bool,
+) -> anyhow::Result<(schema::State<Vec<u8>>, Requires, Unmet, Failed)>
+where
+    I: IntoIterator<Item = String>,
+    F: Fn(&str) -> schema::Options,
+{
+    let mut loaders = packages::packages()?;
+    let mut package_names = package_names.into_iter().collect::<BTreeSet<_>>();
+    let mut packages = BTreeMap::<String, packages::Package>::new();
+    let mut unmet = Unmet::new();
+    let mut failed = Failed::new();
+    let loaded = template::Files::default();
+    // packages including a file of a package not loaded yet are retried later
+    let mut deferred = BTreeSet::new();
+    let mut progress = false;
+    loop {
+        let Some(package_name) = package_names.pop_first() else {
+            if deferred.is_empty() {
+                break;
+            }
+            anyhow::ensure!(progress, "cyclic inclusion among {deferred:?}");
+            package_names = mem::take(&mut deferred);
+            progress = false;
+            continue;
+        };
+        let Some(entry) = loaders.remove(package_name.as_str()) else {
+            let e = anyhow::format_err!("missing package `{package_name}`");
+            fail(&mut failed, keep_going, package_name, e)?;
+            continue;
+        };
+        let conditions = match condition::unmet(&entry.metadata.when) {
+            Ok(conditions) => conditions,
+            Err(e) => {
+                fail(&mut failed, keep_going, package_name, e)?;
+                continue;
+            }
+        };
+        if !conditions.is_empty() {
+            tracing::warn!(package.name = package_name, unmet = ?conditions, "skipped");
+            unmet.entry(package_name).or_default().package = conditions;
+            continue;
+        }
+        let mut package = packages::Package {
+            state: schema::Package {
+                options: options(&package_name),
+                name: package_name,
+                files: BTreeMap::new(),
+                hooks: schema::Hooks::default(),
+                depends: BTreeSet::new(),
+                aggregates: BTreeMap::new(),
+                fragments: BTreeMap::new(),
+                dropins: BTreeSet::new(),
+            },
+            overrides: BTreeMap::new(),
+            declared: schema::Options::new(),
+            requires: entry.metadata.requires.clone(),
+            unmet: BTreeMap::new(),
+            pins: pins.clone(),
+            theme: theme.clone(),
+            fonts: fonts.clone(),
+            fragments: BTreeMap::new(),
+            loaded: loaded.clone(),
+        };
+        let result = (entry.load)(&mut package).and_then(|()| {
+            for key in package.options.keys() {
+                anyhow::ensure!(
+                    package.declared.contains_key(key),
+                    "unknown option `{}.{key}`",
+                    package.name,
+                );
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            if let Some(template::Pending(dependency)) = e
+                .chain()
+                .find_map(|e| e.downcast_ref::<template::Pending>())
+                && !unmet.contains_key(dependency)
+                && !failed.contains_key(dependency)
+            {
+                // only the files of a dependency can be included, otherwise it would be installed unasked
+                if !package.depends.contains(dependency) {
+                    let dependency = dependency.clone();
+                    let e = e.context(format!(
+                        "`{}` has to declare `depends(\"{dependency}\")`, or list it in the `depends` of its manifest, to include its files",
+                        package.name,
+                    ));
+                    fail(&mut failed, keep_going, package.state.name, e)?;
+                    continue;
+                }
+                tracing::debug!(package.name, dependency, "deferred");
+                if !deferred.contains(dependency) {
+                    package_names.insert(dependency.clone());
+                }
+                deferred.insert(package.name.clone());
+                loaders.insert(package.state.name, entry);
+                continue;
+            }
+            fail(&mut failed, keep_going, package.state.name, e)?;
+            continue;
+        }
+        for dependency in &package.depends {
+            if !packages.contains_key(dependency)
+                && !unmet.contains_key(dependency)
+                && !failed.contains_key(dependency)
+                && *dependency != package.name
+            {
+                package_names.insert(dependency.clone());
+            }
+        }
+        loaded.lock().unwrap().insert(
+            package.name.clone(),
+            package
+                .files
+                .iter()
+                .map(|(path, file)| (path.clone(), file.extra.clone()))
+                .collect(),
+        );
+        packages.insert(package.name.clone(), package);
+        progress = true;
+    }
+
+    // a kept package still owns its aggregates and contributes its last fragments
+    let kept = kept(&unmet, &failed);
+    let kept = before
+        .packages
+        .iter()
+        .filter(|package| kept.contains(package.name.as_str()))
+        .collect::<Vec<_>>();
+    aggregate::compose(&mut packages, &kept)?;
+
+    let mut claims = BTreeMap::<_, Vec<_>>::new();
+    for package in packages.values() {
+        for path in package.files.keys() {
+            claims.entry(path).or_default().push(package);
+        }
+    }
+    let mut overridden = Vec::new();
+    for (path, claimants) in claims {
+        if let [first, second, ..] = claimants[..] {
+            let owner = *claimants
+                .iter()
+                .find(|owner| {
+                    claimants.iter().all(|package| {
+                        package.name == owner.name
+                            || owner.overrides.get(path) == Some(&package.name)
+                    })
+                })
+                .ok_or_else(|| {
+                    anyhow::format_err!(
+                        "`{}` is claimed by both `{}` and `{}`",
+                        path.display(),
+                        first.name,
+                        second.name,
+                    )
+                })?;
+            for package in &claimants {
+                if package.name != owner.name {
+                    tracing::info!(?path, package.name, owner = owner.name, "overridden");
+                    overridden.push((package.name.clone(), path.clone()));
+                }
+            }
+        }
     }
+    for (package_name, path) in overridden {
+        if let Some(package) = packages.get_mut(&package_name) {
+            package.files.remove(&path);
+        }
+    }
+
+    for package in packages.values() {
+        if !package.unmet.is_empty() {
+            unmet.entry(package.name.clone()).or_default().files = package.unmet.clone();
+        }
+    }
+    let requires = packages
+        .values()
+        .filter(|package| !package.requires.is_empty())
+        .map(|package| (package.name.clone(), package.requires.clone()))
+        .collect();
+    Ok((
+        schema::State {
+            packages: packages
+                .into_values()
+                .map(|package| package.state)
+                .collect(),
+        },
+        requires,
+        unmet,
+        failed,
+    ))
+}
 
+// with `--keep-going` a package failing to load is reported at the end instead
+fn fail(
+    failed: &mut Failed,
+    keep_going: bool,
+    package_name: String,
+    e: anyhow::Error,
+) -> anyhow::Result<()> {
+    let e = e.context(format!("failed to load `{package_name}`"));
+    if !keep_going {
+        return Err(e);
+    }
+    tracing::error!(package.name = package_name, "{e:#}");
+    failed.insert(package_name, format!("{e:#}"));
     Ok(())
 }
 
+// a file using a secret that is not resolved in a dry run is shown as it was,
+// rather than as changed on every run
+fn unresolved(before: &schema::State<()>, after: &mut schema::State<Vec<u8>>) {
+    let files = before
+        .packages
+        .iter()
+        .flat_map(|package| &package.files)
+        .collect::<BTreeMap<_, _>>();
+    for package in &mut after.packages {
+        for (path, file) in &mut package.files {
+            if secret::names(&file.inputs)
+                .any(|name| file.inputs[&format!("secret.{name}")] == template::UNRESOLVED)
+                && let Some(before) = files.get(path)
+            {
+                tracing::info!(?path, "unresolved secrets, left as it is");
+                file.sha1 = before.sha1;
+                file.mode = before.mode;
+                file.inputs = before.inputs.clone();
+            }
+        }
+    }
+}
+
 fn sync<T>(state: &mut schema::State<T>, orphan: &mut BTreeSet<&Path>) -> anyhow::Result<()> {
     for package in &mut state.packages {
         package.files = mem::take(&mut package.files)
@@ -138,7 +909,7 @@ fn sync<T>(state: &mut schema::State<T>, orphan: &mut BTreeSet<&Path>) -> anyhow
     *orphan = mem::take(orphan)
         .into_iter()
         .filter_map(|path| {
-            path.try_exists()
+            misc::exists(path)
                 .map(|exists| exists.then_some(path))
                 .transpose()
         })
@@ -151,9 +922,7 @@ fn check<P, T>(path: P, mut file: schema::File<T>) -> anyhow::Result<Option<sche
 where
     P: AsRef<Path> + fmt::Debug,
 {
-    if path.as_ref().try_exists()? {
-        let sha1 = misc::sha1(&path)?;
-        let mode = fs::metadata(&path)?.permissions().mode();
+    if let Some((sha1, mode)) = misc::stat(&path, file.mode & misc::S_IFMT == misc::S_IFLNK)? {
         if sha1 != file.sha1 {
             tracing::warn!(
                 actual.sha1 = hex::encode(sha1),
@@ -175,6 +944,33 @@ where
     }
 }
 
+type Changes<'a> = BTreeMap<&'a str, (Option<&'a str>, Option<&'a str>)>;
+
+fn rerendered<'a, T, C>(
+    before: &'a schema::Package<T>,
+    after: &'a schema::Package<C>,
+) -> Vec<(&'a Path, Changes<'a>)> {
+    after
+        .files
+        .iter()
+        .filter_map(|(path, after)| {
+            let before = before.files.get(path)?;
+            let changes = template::changes(&before.inputs, &after.inputs);
+            (before.sha1 != after.sha1 && !changes.is_empty()).then_some((path.as_path(), changes))
+        })
+        .collect()
+}
+
+fn package_name<'a, T, C>(
+    before: &Option<&'a schema::Package<T>>,
+    after: &Option<&'a schema::Package<C>>,
+) -> &'a str {
+    before
+        .map(|package| &package.name)
+        .or(after.map(|package| &package.name))
+        .map_or("", String::as_str)
+}
+
 type Diff<'a, T, C> = (
     tracing::Span,
     Option<&'a schema::Package<T>>,
@@ -185,76 +981,204 @@ fn action<T, C>(
     diff: &Vec<Diff<'_, T, C>>,
     orphan: &BTreeSet<&Path>,
     apply: bool,
+    interactive: bool,
+    skipped: &interactive::Skipped,
 ) -> anyhow::Result<()>
 where
     C: AsRef<[u8]>,
 {
+    let skip_package = |package_name: &String| skipped.packages.contains(package_name.as_str());
+    let skip_path = |path: &Path| skipped.paths.contains(path);
+    let hooks = |before: &Option<&schema::Package<T>>, after: &Option<&schema::Package<C>>| !matches!((before, after), (Some(before), Some(after)) if composed(before, after));
+    let exec = |hook: &schema::Hook| -> anyhow::Result<()> {
+        if !(apply && interactive) || interactive::hook(&hook.command)? {
+            misc::exec(&hook.command, apply)?;
+        }
+        Ok(())
+    };
+
     // pre_remove
-    for (span, before, _) in diff {
-        if let Some(before) = before {
+    for (span, before, after) in diff.iter().rev() {
+        if hooks(before, after)
+            && let Some(before) = before
+            && !skip_package(&before.name)
+        {
             let _enter = span.enter();
             let _span = tracing::info_span!("pre_remove").entered();
             for hook in &before.hooks.pre_remove {
-                misc::exec(&hook.command, apply)?;
+                exec(hook)?;
             }
         }
     }
     // remove
-    for (span, before, _) in diff {
-        if let Some(before) = before {
+    for (span, before, _) in diff.iter().rev() {
+        if let Some(before) = before
+            && !skip_package(&before.name)
+        {
             let _enter = span.enter();
             for path in before.files.keys() {
+                if skip_path(path) {
+                    continue;
+                }
                 misc::remove(path, apply)?;
             }
         }
     }
     {
         for path in orphan {
+            if skip_path(path) {
+                continue;
+            }
             let _span = tracing::info_span!("remove", ?path).entered();
             tracing::warn!("orphan");
             misc::remove(path, apply)?;
         }
     }
     // post_remove
-    for (span, before, _) in diff {
-        if let Some(before) = before {
+    for (span, before, after) in diff.iter().rev() {
+        if hooks(before, after)
+            && let Some(before) = before
+            && !skip_package(&before.name)
+        {
             let _enter = span.enter();
             let _span = tracing::info_span!("post_remove").entered();
             for hook in &before.hooks.post_remove {
-                misc::exec(&hook.command, apply)?;
+                exec(hook)?;
             }
         }
     }
 
     // pre_install
-    for (span, _, after) in diff {
-        if let Some(after) = after {
+    for (span, before, after) in diff {
+        if hooks(before, after)
+            && let Some(after) = after
+            && !skip_package(&after.name)
+        {
             let _enter = span.enter();
             let _span = tracing::info_span!("pre_install").entered();
             for hook in &after.hooks.pre_install {
-                misc::exec(&hook.command, apply)?;
+                exec(hook)?;
             }
         }
     }
     // install
     for (span, _, after) in diff {
-        if let Some(after) = after {
+        if let Some(after) = after
+            && !skip_package(&after.name)
+        {
             let _enter = span.enter();
             for (path, file) in &after.files {
+                if skip_path(path) {
+                    continue;
+                }
                 misc::install(path, &file.extra, file.mode, apply)?;
             }
         }
     }
     // post_install
-    for (span, _, after) in diff {
-        if let Some(after) = after {
+    for (span, before, after) in diff {
+        if hooks(before, after)
+            && let Some(after) = after
+            && !skip_package(&after.name)
+        {
             let _enter = span.enter();
             let _span = tracing::info_span!("post_install").entered();
             for hook in &after.hooks.post_install {
-                misc::exec(&hook.command, apply)?;
+                exec(hook)?;
             }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(package_name: &str, depends: &[&str], files: &[(&str, u8)]) -> schema::Package<()> {
+        let mut package = schema::Package::new(package_name);
+        package.depends = depends.iter().map(|name| name.to_string()).collect();
+        for (path, sha1) in files {
+            let file = schema::File {
+                sha1: [*sha1; 20],
+                mode: 0o100644,
+                inputs: BTreeMap::new(),
+                extra: (),
+            };
+            package.files.insert(PathBuf::from(path), file);
+        }
+        package
+    }
+
+    fn names<T, C>(diff: &[Diff<'_, T, C>]) -> Vec<String> {
+        diff.iter()
+            .map(|(_, before, after)| package_name(before, after).to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn diff_in_dependency_order() {
+        let before = schema::State {
+            packages: vec![package("old", &["base"], &[("/old", 0)])],
+        };
+        let after = schema::State {
+            packages: vec![
+                package("a", &["c"], &[("/a", 0)]),
+                package("b", &[], &[("/b", 0)]),
+                package("base", &[], &[("/base", 0)]),
+                package("c", &["b", "base"], &[("/c", 0)]),
+            ],
+        };
+        let diff = diff(&before, &after, &BTreeSet::new());
+        assert_eq!(names(&diff), ["b", "base", "c", "old", "a"]);
+    }
+
+    #[test]
+    fn diff_of_changes() {
+        let before = schema::State {
+            packages: vec![
+                package("same", &[], &[("/same", 0)]),
+                package("changed", &[], &[("/changed", 0)]),
+            ],
+        };
+        let after = schema::State {
+            packages: vec![
+                package("same", &[], &[("/same", 0)]),
+                package("changed", &[], &[("/changed", 1)]),
+            ],
+        };
+        assert_eq!(names(&diff(&before, &after, &BTreeSet::new())), ["changed"]);
+        let reinstall = BTreeSet::from(["same".to_owned()]);
+        assert_eq!(
+            names(&diff(&before, &after, &reinstall)),
+            ["changed", "same"]
+        );
+    }
+
+    #[test]
+    fn composed_aggregates() {
+        let before = package("owner", &[], &[("/config", 0), ("/agg", 0)]);
+        let mut after = package("owner", &[], &[("/config", 0), ("/agg", 1)]);
+        assert!(!composed(&before, &after));
+        after
+            .aggregates
+            .insert("/agg".into(), schema::Aggregate::File);
+        assert!(composed(&before, &after));
+        after.files.get_mut(Path::new("/config")).unwrap().sha1 = [1; 20];
+        assert!(!composed(&before, &after));
+
+        // reinstalled as it was
+        assert!(!composed(&before, &before));
+
+        // a contributor whose fragment in a drop-in directory changed
+        let mut before = package("contributor", &[], &[("/config", 0), ("/dir/10", 0)]);
+        before.dropins.insert("/dir/10".into());
+        let mut after = package("contributor", &[], &[("/config", 0), ("/dir/10", 1)]);
+        after.dropins.insert("/dir/10".into());
+        assert!(composed(&before, &after));
+        after.files.remove(Path::new("/dir/10"));
+        after.dropins.clear();
+        assert!(composed(&before, &after));
+    }
+}
@@ -0,0 +1,132 @@
+use crate::packages::{Entry, Package, PackageExt};
+use crate::{context, misc, schema, template};
+use anyhow::Context;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub fn dir() -> anyhow::Result<PathBuf> {
+    Ok(misc::config_dir()?.join("packages"))
+}
+
+pub fn manifests(dir: &Path) -> anyhow::Result<BTreeMap<String, Entry>> {
+    let mut entries = BTreeMap::new();
+    if !dir.try_exists()? {
+        return Ok(entries);
+    }
+    for entry in fs::read_dir(dir)? {
+        let base = entry?.path();
+        let path = base.join("package.toml");
+        if !path.try_exists()? {
+            continue;
+        }
+        let package_name = base
+            .file_name()
+            .and_then(|package_name| package_name.to_str())
+            .ok_or_else(|| anyhow::format_err!("invalid package name {base:?}"))?
+            .to_owned();
+        // a broken manifest only takes its own package down, when it is loaded
+        let manifest = match fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|s| Ok(toml::from_str::<schema::Manifest>(&s)?))
+        {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                let e = format!("invalid manifest `{}`: {e:#}", path.display());
+                let entry = Entry {
+                    metadata: schema::Metadata::default(),
+                    load: Box::new(move |_: &mut _| Err(anyhow::Error::msg(e.clone()))),
+                };
+                entries.insert(package_name, entry);
+                continue;
+            }
+        };
+        tracing::debug!(package.name = package_name, ?path, "manifest");
+        entries.insert(
+            package_name,
+            Entry {
+                metadata: manifest.metadata.clone(),
+                load: Box::new(move |package: &mut _| load(package, &base, &manifest)),
+            },
+        );
+    }
+    Ok(entries)
+}
+
+fn load(package: &mut Package, base: &Path, manifest: &schema::Manifest) -> anyhow::Result<()> {
+    for dependency in &manifest.depends {
+        package.depends(dependency);
+    }
+    for (name, default) in &manifest.options {
+        package.option(name, default.clone())?;
+    }
+    for file in &manifest.files {
+        if !package.when(&file.path, &file.when)? {
+            continue;
+        }
+        let mode = file.mode.map(|mode| {
+            if mode & misc::S_IFMT == 0 {
+                mode | 0o100000
+            } else {
+                mode
+            }
+        });
+        match &file.content {
+            schema::ManifestContent::Source(source) => {
+                let source = base.join(source);
+                let content = fs::read(&source)
+                    .with_context(|| format!("failed to read `{}`", source.display()))?;
+                package.file(&file.path, content, mode)?;
+            }
+            schema::ManifestContent::Template(template) => {
+                let template = base.join(template);
+                let rendered = render(&template, package, file.format)?;
+                package.rendered(&file.path, rendered, mode)?;
+            }
+            schema::ManifestContent::Symlink(target) => package.symlink(&file.path, target)?,
+        }
+    }
+    for (path, aggregate) in &manifest.aggregates {
+        package.aggregate(path, *aggregate)?;
+    }
+    for fragment in &manifest.fragments {
+        let content = match &fragment.content {
+            schema::ManifestContent::Source(source) => {
+                let source = base.join(source);
+                fs::read(&source)
+                    .with_context(|| format!("failed to read `{}`", source.display()))?
+            }
+            schema::ManifestContent::Template(template) => {
+                let template = base.join(template);
+                render(&template, package, fragment.format)?
+                    .content
+                    .into_bytes()
+            }
+            schema::ManifestContent::Symlink(_) => anyhow::bail!(
+                "fragment `{}` of `{}` cannot be a symlink",
+                fragment.key,
+                fragment.target.display(),
+            ),
+        };
+        package.fragment(&fragment.target, &fragment.key, content)?;
+    }
+    let hooks = manifest.hooks.clone();
+    package.hooks.pre_install.extend(hooks.pre_install);
+    package.hooks.post_install.extend(hooks.post_install);
+    package.hooks.pre_remove.extend(hooks.pre_remove);
+    package.hooks.post_remove.extend(hooks.post_remove);
+    for (path, package_name) in &manifest.overrides {
+        package.overrides(path, package_name)?;
+    }
+    Ok(())
+}
+
+fn render(
+    path: &Path,
+    package: &Package,
+    format: Option<schema::Format>,
+) -> anyhow::Result<template::Rendered> {
+    let source =
+        fs::read_to_string(path).with_context(|| format!("failed to read `{}`", path.display()))?;
+    template::render(path, &source, &context::Context::new(package)?, format)
+}
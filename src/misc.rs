@@ -1,24 +1,65 @@
 use sha1::{Digest, Sha1};
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
-use std::fs::{self, File, Permissions};
-use std::io;
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
-use std::process::Command;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, Write};
+use std::os::unix;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
 
 serde_with::serde_conv!(pub Octal, u32, |value| format!("{value:o}"), |value: String| {
     u32::from_str_radix(&value, 8)
 });
 
+pub fn config_dir() -> anyhow::Result<PathBuf> {
+    Ok(dirs::config_dir()
+        .ok_or_else(|| anyhow::format_err!("missing config_dir"))?
+        .join(env!("CARGO_BIN_NAME")))
+}
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFLNK: u32 = 0o120000;
+
+// a managed symlink is compared by its target, any other file by its content,
+// following a symlink the user may have put in its place
 #[tracing::instrument(err)]
-pub fn sha1<P>(path: P) -> io::Result<[u8; 20]>
+pub fn stat<P>(path: P, symlink: bool) -> io::Result<Option<([u8; 20], u32)>>
 where
     P: AsRef<Path> + fmt::Debug,
 {
+    let metadata = if symlink {
+        fs::symlink_metadata(&path)
+    } else {
+        fs::metadata(&path)
+    };
+    let metadata = match metadata {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
     let mut hasher = Sha1::new();
-    io::copy(&mut File::open(path)?, &mut hasher)?;
-    Ok(hasher.finalize().into())
+    if metadata.is_symlink() {
+        hasher.update(fs::read_link(path)?.as_os_str().as_bytes());
+    } else {
+        io::copy(&mut File::open(path)?, &mut hasher)?;
+    }
+    Ok(Some((
+        hasher.finalize().into(),
+        metadata.permissions().mode(),
+    )))
+}
+
+pub fn exists<P>(path: P) -> io::Result<bool>
+where
+    P: AsRef<Path>,
+{
+    match fs::symlink_metadata(path) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
 }
 
 #[tracing::instrument(err, ret)]
@@ -42,8 +83,33 @@ where
         if let Some(parent) = path.as_ref().parent() {
             fs::create_dir_all(parent)?;
         }
-        fs::write(&path, content)?;
-        fs::set_permissions(&path, Permissions::from_mode(mode))?;
+        if mode & S_IFMT == S_IFLNK {
+            if exists(&path)? {
+                fs::remove_file(&path)?;
+            }
+            unix::fs::symlink(OsStr::from_bytes(content.as_ref()), &path)?;
+        } else {
+            // written aside, never readable beyond its mode, and renamed over the file
+            let mut name = OsString::from(".");
+            name.push(path.as_ref().file_name().unwrap_or_default());
+            name.push(format!(".{}", process::id()));
+            let temp = path.as_ref().with_file_name(name);
+            let result = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(mode)
+                .open(&temp)
+                .and_then(|mut file| {
+                    file.write_all(content.as_ref())?;
+                    // `mode` is narrowed by the umask
+                    file.set_permissions(Permissions::from_mode(mode))?;
+                    fs::rename(&temp, &path)
+                });
+            if result.is_err() {
+                let _ = fs::remove_file(&temp);
+            }
+            result?;
+        }
     }
     Ok(())
 }
@@ -64,3 +130,22 @@ where
     }
     Ok(())
 }
+
+pub fn ask(prompt: fmt::Arguments) -> io::Result<Option<String>> {
+    let mut stderr = io::stderr().lock();
+    write!(stderr, "{prompt} ")?;
+    stderr.flush()?;
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line)? == 0 {
+        Ok(None)
+    } else {
+        Ok(Some(line.trim().to_lowercase()))
+    }
+}
+
+pub fn confirm(prompt: fmt::Arguments) -> io::Result<bool> {
+    Ok(matches!(
+        ask(format_args!("{prompt} [y/N]"))?.as_deref(),
+        Some("y" | "yes"),
+    ))
+}
@@ -1,60 +1,354 @@
-use crate::schema;
+use crate::{condition, manifest, misc, schema, template};
+use clap_complete::env::{self, EnvCompleter};
 use sha1::{Digest, Sha1};
-use std::collections::BTreeMap;
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-type Package = schema::Package<Vec<u8>>;
+// a package being loaded: what is persisted in the state,
+// and what it is loaded from or contributes only while loading
+pub struct Package {
+    pub state: schema::Package<Vec<u8>>,
+    pub declared: schema::Options,
+    pub overrides: BTreeMap<PathBuf, String>,
+    pub requires: BTreeSet<String>,
+    pub unmet: BTreeMap<PathBuf, Vec<schema::Condition>>,
+    pub pins: BTreeMap<String, String>,
+    pub loaded: template::Files,
+    pub theme: Arc<schema::Theme>,
+    pub fonts: Arc<schema::Fonts>,
+    pub fragments: BTreeMap<PathBuf, BTreeMap<String, Vec<u8>>>,
+}
+
+impl Deref for Package {
+    type Target = schema::Package<Vec<u8>>;
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+impl DerefMut for Package {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.state
+    }
+}
+
+// an empty package for tests to fill in
+#[cfg(test)]
+impl Package {
+    pub fn new(name: &str) -> Self {
+        Self {
+            state: schema::Package::new(name),
+            declared: schema::Options::new(),
+            overrides: BTreeMap::new(),
+            requires: BTreeSet::new(),
+            unmet: BTreeMap::new(),
+            pins: BTreeMap::new(),
+            loaded: Arc::default(),
+            theme: Arc::default(),
+            fonts: Arc::default(),
+            fragments: BTreeMap::new(),
+        }
+    }
+}
+
+const SCRATCH: &str = "/scratch";
+pub type Loader = Box<dyn Fn(&mut Package) -> anyhow::Result<()>>;
+
+pub struct Entry {
+    pub metadata: schema::Metadata,
+    pub load: Loader,
+}
+
+pub fn packages() -> anyhow::Result<BTreeMap<String, Entry>> {
+    let mut packages = builtins()
+        .into_iter()
+        .map(|(package_name, (metadata, load))| {
+            let load = Box::new(load) as Loader;
+            (package_name.to_owned(), Entry { metadata, load })
+        })
+        .collect::<BTreeMap<_, _>>();
+    for (package_name, entry) in manifest::manifests(&manifest::dir()?)? {
+        if packages.insert(package_name.clone(), entry).is_some() {
+            tracing::info!(package.name = package_name, "manifest overrides built-in");
+        }
+    }
+    Ok(packages)
+}
+
+type Builtin = (schema::Metadata, fn(&mut Package) -> anyhow::Result<()>);
 
-pub fn packages() -> BTreeMap<&'static str, fn(&mut Package) -> anyhow::Result<()>> {
+fn builtins() -> BTreeMap<&'static str, Builtin> {
     [
-        ("atuin", atuin as _),
-        ("base", base as _),
-        ("cargo", cargo as _),
-        ("emacs", emacs as _),
-        ("fcitx5", fcitx5 as _),
-        ("firefox", firefox as _),
-        ("ghq", ghq as _),
-        ("google-cloud-cli", google_cloud_cli as _),
-        ("npm", npm as _),
-        ("paru", paru as _),
-        ("podman", podman as _),
-        ("slack", slack as _),
-        ("ssh", ssh as _),
-        ("starship", starship as _),
-        ("sway", sway as _),
-        ("tmux", tmux as _),
-        ("uv", uv as _),
+        (
+            env!("CARGO_BIN_NAME"),
+            metadata(
+                "Shell completions for akabei itself",
+                [],
+                ["bash-completion", "fish", "zsh"],
+                [],
+            ),
+            akabei as _,
+        ),
+        (
+            "atuin",
+            metadata(
+                "Shell history sync with atuin",
+                ["atuin", "bash-preexec"],
+                [],
+                [],
+            ),
+            atuin as _,
+        ),
+        (
+            "base",
+            metadata(
+                "Bash profile and XDG user directories",
+                ["xdg-user-dirs"],
+                [],
+                [],
+            ),
+            base as _,
+        ),
+        (
+            "cargo",
+            metadata(
+                "Rust toolchain with build artifacts on scratch",
+                ["rustup"],
+                [],
+                [],
+            ),
+            cargo as _,
+        ),
+        (
+            "emacs",
+            schema::Metadata {
+                when: vec![schema::Condition::Exists(
+                    "/usr/share/applications/emacs.desktop".into(),
+                )],
+                ..metadata("Emacs launched in its own systemd scope", ["emacs"], [], [])
+            },
+            emacs as _,
+        ),
+        (
+            "fcitx5",
+            metadata(
+                "Fcitx5 input method with SKK",
+                ["fcitx5-skk"],
+                ["fcitx5-gtk", "fcitx5-qt"],
+                [],
+            ),
+            fcitx5 as _,
+        ),
+        (
+            "firefox",
+            metadata(
+                "Firefox launched in its own systemd scope",
+                ["firefox"],
+                [],
+                [],
+            ),
+            firefox as _,
+        ),
+        (
+            "fonts",
+            metadata(
+                "Fontconfig preferences for the configured fonts",
+                [
+                    "fontconfig",
+                    "noto-fonts",
+                    "noto-fonts-cjk",
+                    "noto-fonts-emoji",
+                    "ttf-iosevka-nerd",
+                ],
+                [],
+                [],
+            ),
+            fonts as _,
+        ),
+        (
+            "ghq",
+            metadata(
+                "Repository management with ghq and skim",
+                ["ghq", "skim"],
+                [],
+                ["ghq-cd"],
+            ),
+            ghq as _,
+        ),
+        (
+            "google-cloud-cli",
+            metadata(
+                "Google Cloud CLI running in a container",
+                ["podman"],
+                [],
+                ["docker-credential-gcloud", "gcloud"],
+            ),
+            google_cloud_cli as _,
+        ),
+        (
+            "npm",
+            metadata("npm with its cache on scratch", ["npm"], [], []),
+            npm as _,
+        ),
+        (
+            "paru",
+            metadata(
+                "AUR helper with its clone directory on scratch",
+                ["paru"],
+                [],
+                [],
+            ),
+            paru as _,
+        ),
+        (
+            "podman",
+            metadata(
+                "Rootless podman with storage on scratch",
+                ["podman"],
+                [],
+                [],
+            ),
+            podman as _,
+        ),
+        (
+            "slack",
+            schema::Metadata {
+                when: vec![schema::Condition::Exists(
+                    "/usr/share/applications/slack.desktop".into(),
+                )],
+                ..metadata("Slack launched in its own systemd scope", ["slack"], [], [])
+            },
+            slack as _,
+        ),
+        (
+            "ssh",
+            metadata("OpenSSH client configuration", ["openssh"], [], []),
+            ssh as _,
+        ),
+        (
+            "starship",
+            metadata("Starship shell prompt", ["starship"], [], []),
+            starship as _,
+        ),
+        (
+            "sway",
+            metadata(
+                "Sway desktop with foot, fuzzel, i3status-rust and swayidle",
+                [
+                    "foot",
+                    "fuzzel",
+                    "i3status-rust",
+                    "otf-font-awesome",
+                    "sway",
+                    "swaybg",
+                    "swayidle",
+                    "swaylock",
+                ],
+                ["xorg-xwayland"],
+                [],
+            ),
+            sway as _,
+        ),
+        (
+            "tmux",
+            metadata("Terminal multiplexer configuration", ["tmux"], [], []),
+            tmux as _,
+        ),
+        (
+            "uv",
+            metadata("uv with its cache on scratch", ["uv"], [], []),
+            uv as _,
+        ),
     ]
     .into_iter()
+    .map(|(package_name, metadata, load)| (package_name, (metadata, load)))
     .collect()
 }
 
+fn metadata<const R: usize, const O: usize, const P: usize>(
+    description: &str,
+    requires: [&str; R],
+    optional: [&str; O],
+    provides: [&str; P],
+) -> schema::Metadata {
+    schema::Metadata {
+        description: description.to_owned(),
+        requires: requires.into_iter().map(str::to_owned).collect(),
+        optional: optional.into_iter().map(str::to_owned).collect(),
+        provides: provides.into_iter().map(str::to_owned).collect(),
+        when: Vec::new(),
+    }
+}
+
 macro_rules! template {
-    ($path:literal) => {{
+    ($package:expr, $path:literal) => {
+        template!($package, $path, "none")
+    };
+    ($package:expr, $path:literal, $escape:literal) => {{
         #[derive(::askama::Template)]
-        #[template(escape = "none", path = $path)]
-        #[allow(dead_code)]
+        #[template(escape = $escape, path = $path)]
         struct Template {
-            env: ::std::collections::BTreeMap<String, String>,
-            uid: u32,
+            context: $crate::context::Context,
         }
 
-        let template = Template {
-            env: ::std::env::vars().collect(),
-            uid: ::nix::unistd::getuid().as_raw(),
-        };
-        ::askama::Template::render(&template).map(|mut s| {
-            if !s.ends_with('\n') {
-                s.push('\n');
+        impl ::std::ops::Deref for Template {
+            type Target = $crate::context::Context;
+            fn deref(&self) -> &Self::Target {
+                &self.context
             }
-            s
-        })
+        }
+
+        // custom filters are looked up as `filters::<name>`
+        #[allow(unused_imports)]
+        use $crate::template::filters;
+
+        let context = $crate::context::Context::new($package)?;
+        match $crate::template::runtime($path)? {
+            Some((path, source)) => $crate::template::render(
+                &path,
+                &source,
+                &context,
+                $crate::template::format($escape),
+            ),
+            None => {
+                let content = ::askama::Template::render(&Template {
+                    context: context.clone(),
+                });
+                $crate::template::compiled($path.as_ref(), content, &context)
+            }
+        }
     }};
 }
 
-// atuin
-// bash-preexec
+fn akabei(package: &mut Package) -> anyhow::Result<()> {
+    let bin = env!("CARGO_BIN_NAME");
+    let mut s = Vec::new();
+    env::Bash.write_registration("COMPLETE", bin, bin, bin, &mut s)?;
+    package.file(
+        format!(".local/share/bash-completion/completions/{bin}"),
+        s,
+        None,
+    )?;
+    let mut s = Vec::new();
+    env::Fish.write_registration("COMPLETE", bin, bin, bin, &mut s)?;
+    package.file(format!(".config/fish/completions/{bin}.fish"), s, None)?;
+    // zsh does not look here unless the snippet adding it to `fpath` is sourced
+    let mut s = Vec::new();
+    env::Zsh.write_registration("COMPLETE", bin, bin, bin, &mut s)?;
+    package.file(format!(".local/share/zsh/site-functions/_{bin}"), s, None)?;
+    package.file(
+        format!(".local/share/zsh/{bin}.zsh"),
+        include_str!("packages/akabei/akabei.zsh"),
+        None,
+    )?;
+    Ok(())
+}
+
 fn atuin(package: &mut Package) -> anyhow::Result<()> {
+    package.depends("base");
     package.file(
         // https://github.com/atuinsh/atuin/issues/2738#issuecomment-2876082481
         ".bashrc.d/60-atuin.bash",
@@ -70,7 +364,6 @@ fn atuin(package: &mut Package) -> anyhow::Result<()> {
     Ok(())
 }
 
-// xdg-user-dirs
 fn base(package: &mut Package) -> anyhow::Result<()> {
     package.file(".bashrc", include_str!("packages/base/bashrc.bash"), None)?;
     package.file(
@@ -82,22 +375,22 @@ fn base(package: &mut Package) -> anyhow::Result<()> {
     Ok(())
 }
 
-// rustup
 fn cargo(package: &mut Package) -> anyhow::Result<()> {
+    package.depends("base");
+    package.option("scratch", SCRATCH)?;
     package.file(
         ".bashrc.d/50-cargo.bash",
         include_str!("packages/cargo/cargo.bash"),
         None,
     )?;
-    package.file(
+    package.rendered(
         ".cargo/config.toml",
-        template!("packages/cargo/config.toml")?,
+        template!(package, "packages/cargo/config.toml", "toml")?,
         None,
     )?;
     Ok(())
 }
 
-// emacs
 fn emacs(package: &mut Package) -> anyhow::Result<()> {
     let mut desktop = ini::Ini::load_from_file("/usr/share/applications/emacs.desktop")?;
     for (_, properties) in &mut desktop {
@@ -116,10 +409,8 @@ fn emacs(package: &mut Package) -> anyhow::Result<()> {
     Ok(())
 }
 
-// fcitx5-gtk
-// fcitx5-qt
-// fcitx5-skk
 fn fcitx5(package: &mut Package) -> anyhow::Result<()> {
+    package.depends("base");
     package.file(
         ".bashrc.d/50-fcitx5.bash",
         include_str!("packages/fcitx5/fcitx5.bash"),
@@ -150,39 +441,57 @@ fn fcitx5(package: &mut Package) -> anyhow::Result<()> {
         include_str!("packages/fcitx5/dictionary_list"),
         None,
     )?;
+    package.fragment(
+        ".config/sway/config.d",
+        "50-fcitx5",
+        include_str!("packages/fcitx5/sway.config"),
+    )?;
     package.post_install(["systemctl", "--user", "daemon-reload"]);
     package.post_install(["systemctl", "--user", "enable", "fcitx5.service"]);
     package.pre_remove(["systemctl", "--user", "disable", "fcitx5.service"]);
     Ok(())
 }
 
-// firefox
 fn firefox(package: &mut Package) -> anyhow::Result<()> {
+    package.depends("base");
     package.file(
         ".bashrc.d/50-firefox.bash",
         include_str!("packages/firefox/firefox.bash"),
         None,
     )?;
-    let mut desktop = ini::Ini::load_from_file("/usr/share/applications/firefox.desktop")?;
-    for (_, properties) in &mut desktop {
-        for (k, v) in properties {
-            if k == "Exec" {
-                v.insert_str(
-                    0,
-                    "/usr/bin/systemd-run --user --quiet --scope --slice=firefox.slice ",
-                );
+    let path = ".local/share/applications/firefox.desktop";
+    let source = "/usr/share/applications/firefox.desktop";
+    if package.when(path, &[schema::Condition::Exists(source.into())])? {
+        let mut desktop = ini::Ini::load_from_file(source)?;
+        for (_, properties) in &mut desktop {
+            for (k, v) in properties {
+                if k == "Exec" {
+                    v.insert_str(
+                        0,
+                        "/usr/bin/systemd-run --user --quiet --scope --slice=firefox.slice ",
+                    );
+                }
             }
         }
+        let mut s = Vec::new();
+        desktop.write_to(&mut s)?;
+        package.file(path, s, None)?;
     }
-    let mut s = Vec::new();
-    desktop.write_to(&mut s)?;
-    package.file(".local/share/applications/firefox.desktop", s, None)?;
     Ok(())
 }
 
-// ghq
-// skim
+fn fonts(package: &mut Package) -> anyhow::Result<()> {
+    package.rendered(
+        ".config/fontconfig/fonts.conf",
+        template!(package, "packages/fonts/fonts.conf", "xml")?,
+        None,
+    )?;
+    package.post_install(["fc-cache", "--force"]);
+    Ok(())
+}
+
 fn ghq(package: &mut Package) -> anyhow::Result<()> {
+    package.depends("base");
     package.file(
         ".bashrc.d/50-ghq.bash",
         include_str!("packages/ghq/ghq.bash"),
@@ -191,8 +500,9 @@ fn ghq(package: &mut Package) -> anyhow::Result<()> {
     Ok(())
 }
 
-// podman
 fn google_cloud_cli(package: &mut Package) -> anyhow::Result<()> {
+    package.depends("base");
+    package.depends("podman");
     package.file(
         ".bashrc.d/50-google-cloud-cli.bash",
         include_str!("packages/google-cloud-cli/google-cloud-cli.bash"),
@@ -218,24 +528,29 @@ fn google_cloud_cli(package: &mut Package) -> anyhow::Result<()> {
     Ok(())
 }
 
-// npm
 fn npm(package: &mut Package) -> anyhow::Result<()> {
-    package.file(".npmrc", template!("packages/npm/npmrc")?, None)?;
+    package.option("scratch", SCRATCH)?;
+    package.rendered(
+        ".npmrc",
+        template!(package, "packages/npm/npmrc", "ini")?,
+        None,
+    )?;
     Ok(())
 }
 
-// paru
 fn paru(package: &mut Package) -> anyhow::Result<()> {
-    package.file(
+    package.option("scratch", SCRATCH)?;
+    package.rendered(
         ".config/paru/paru.conf",
-        template!("packages/paru/paru.conf")?,
+        template!(package, "packages/paru/paru.conf", "ini")?,
         None,
     )?;
     Ok(())
 }
 
-// podman
 fn podman(package: &mut Package) -> anyhow::Result<()> {
+    package.depends("base");
+    package.option("scratch", SCRATCH)?;
     package.file(
         ".bashrc.d/50-podman.bash",
         include_str!("packages/podman/podman.bash"),
@@ -246,15 +561,14 @@ fn podman(package: &mut Package) -> anyhow::Result<()> {
         include_str!("packages/podman/containers.conf"),
         None,
     )?;
-    package.file(
+    package.rendered(
         ".config/containers/storage.conf",
-        template!("packages/podman/storage.conf")?,
+        template!(package, "packages/podman/storage.conf", "toml")?,
         None,
     )?;
     Ok(())
 }
 
-// slack
 fn slack(package: &mut Package) -> anyhow::Result<()> {
     let mut desktop = ini::Ini::load_from_file("/usr/share/applications/slack.desktop")?;
     for (_, properties) in &mut desktop {
@@ -273,19 +587,23 @@ fn slack(package: &mut Package) -> anyhow::Result<()> {
     Ok(())
 }
 
-// openssh
 fn ssh(package: &mut Package) -> anyhow::Result<()> {
+    package.depends("base");
     package.file(
         ".bashrc.d/50-ssh-agent.bash",
         include_str!("packages/ssh/ssh-agent.bash"),
         None,
     )?;
-    package.file(".ssh/config", template!("packages/ssh/config")?, None)?;
+    package.rendered(
+        ".ssh/config",
+        template!(package, "packages/ssh/config")?,
+        None,
+    )?;
     Ok(())
 }
 
-// starship
 fn starship(package: &mut Package) -> anyhow::Result<()> {
+    package.depends("base");
     package.file(
         ".bashrc.d/50-starship.bash",
         include_str!("packages/starship/starship.bash"),
@@ -294,45 +612,39 @@ fn starship(package: &mut Package) -> anyhow::Result<()> {
     Ok(())
 }
 
-// foot
-// fuzzel
-// i3status-rust
-// noto-fonts
-// noto-fonts-cjk
-// noto-fonts-emoji
-// otf-font-awesome
-// sway
-// swaybg
-// swayidle
-// swaylock
-// ttf-iosevka-nerd
-// xorg-xwayland
 fn sway(package: &mut Package) -> anyhow::Result<()> {
+    package.depends("base");
+    package.depends("fonts");
+    package.option(
+        "wallpaper",
+        "/usr/share/backgrounds/sway/Sway_Wallpaper_Blue_1920x1080.png",
+    )?;
     package.file(
         ".bashrc.d/50-sway.bash",
         include_str!("packages/sway/sway.bash"),
         None,
     )?;
-    package.file(
+    package.rendered(
         ".config/foot/foot.ini",
-        include_str!("packages/sway/foot.ini"),
+        template!(package, "packages/sway/foot.ini", "ini")?,
         None,
     )?;
-    package.file(
+    package.rendered(
         ".config/fuzzel/fuzzel.ini",
-        include_str!("packages/sway/fuzzel.ini"),
+        template!(package, "packages/sway/fuzzel.ini", "ini")?,
         None,
     )?;
-    package.file(
+    package.rendered(
         ".config/i3status-rust/config.toml",
-        include_str!("packages/sway/i3status-rust.toml"),
+        template!(package, "packages/sway/i3status-rust.toml", "toml")?,
         None,
     )?;
-    package.file(
+    package.rendered(
         ".config/sway/config",
-        include_str!("packages/sway/config"),
+        template!(package, "packages/sway/config")?,
         None,
     )?;
+    package.aggregate(".config/sway/config.d", schema::Aggregate::Dir)?;
     package.file(
         ".config/systemd/user/swayidle.service",
         include_str!("packages/sway/swayidle.service"),
@@ -354,28 +666,56 @@ fn sway(package: &mut Package) -> anyhow::Result<()> {
     Ok(())
 }
 
-// tmux
 fn tmux(package: &mut Package) -> anyhow::Result<()> {
-    package.file(
+    package.rendered(
         ".config/tmux/tmux.conf",
-        include_str!("packages/tmux/tmux.conf"),
+        template!(package, "packages/tmux/tmux.conf")?,
         None,
     )?;
     Ok(())
 }
 
-// uv
 fn uv(package: &mut Package) -> anyhow::Result<()> {
-    package.file(
+    package.option("scratch", SCRATCH)?;
+    package.rendered(
         ".config/uv/uv.toml",
-        template!("packages/uv/uv.toml")?,
+        template!(package, "packages/uv/uv.toml", "toml")?,
         None,
     )?;
     Ok(())
 }
 
-trait PackageExt {
+pub trait PackageExt {
+    fn depends(&mut self, package_name: &str);
+    fn option<V>(&mut self, name: &str, default: V) -> anyhow::Result<()>
+    where
+        V: Into<toml::Value>;
     fn file<P, C>(&mut self, path: P, content: C, mode: Option<u32>) -> anyhow::Result<()>
+    where
+        P: AsRef<Path>,
+        C: AsRef<[u8]>;
+    fn rendered<P>(
+        &mut self,
+        path: P,
+        rendered: template::Rendered,
+        mode: Option<u32>,
+    ) -> anyhow::Result<()>
+    where
+        P: AsRef<Path>;
+    fn symlink<P, Q>(&mut self, path: P, target: Q) -> anyhow::Result<()>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>;
+    fn overrides<P>(&mut self, path: P, package_name: &str) -> anyhow::Result<()>
+    where
+        P: AsRef<Path>;
+    fn when<P>(&mut self, path: P, conditions: &[schema::Condition]) -> anyhow::Result<bool>
+    where
+        P: AsRef<Path>;
+    fn aggregate<P>(&mut self, path: P, aggregate: schema::Aggregate) -> anyhow::Result<()>
+    where
+        P: AsRef<Path>;
+    fn fragment<P, C>(&mut self, target: P, key: &str, content: C) -> anyhow::Result<()>
     where
         P: AsRef<Path>,
         C: AsRef<[u8]>;
@@ -394,31 +734,142 @@ trait PackageExt {
 }
 
 impl PackageExt for Package {
+    fn depends(&mut self, package_name: &str) {
+        self.depends.insert(package_name.to_owned());
+    }
+
+    fn option<V>(&mut self, name: &str, default: V) -> anyhow::Result<()>
+    where
+        V: Into<toml::Value>,
+    {
+        let default = default.into();
+        let value = match self.options.get(name) {
+            Some(value) if value.type_str() == default.type_str() => value.clone(),
+            Some(value) => anyhow::bail!(
+                "option `{}.{name}` expects {}, got {}",
+                self.name,
+                default.type_str(),
+                value.type_str(),
+            ),
+            None => default,
+        };
+        self.declared.insert(name.to_owned(), value);
+        Ok(())
+    }
+
     fn file<P, C>(&mut self, path: P, content: C, mode: Option<u32>) -> anyhow::Result<()>
     where
         P: AsRef<Path>,
         C: AsRef<[u8]>,
     {
-        let path = if path.as_ref().is_relative() {
-            dirs::home_dir()
-                .ok_or_else(|| anyhow::format_err!("missing home_dir"))?
-                .join(path)
-        } else {
-            path.as_ref().to_path_buf()
-        };
         let sha1 = Sha1::digest(content.as_ref()).into();
         let mode = mode.unwrap_or(0o100644);
         self.files.insert(
-            path,
+            home(path)?,
             schema::File {
                 sha1,
                 mode,
+                inputs: BTreeMap::new(),
                 extra: content.as_ref().to_vec(),
             },
         );
         Ok(())
     }
 
+    fn rendered<P>(
+        &mut self,
+        path: P,
+        rendered: template::Rendered,
+        mode: Option<u32>,
+    ) -> anyhow::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        let path = home(path)?;
+        // files containing secrets are readable by the owner only
+        let mode = if rendered.secret {
+            Some(mode.unwrap_or(0o100644) & !0o077)
+        } else {
+            mode
+        };
+        self.file(&path, rendered.content, mode)?;
+        if let Some(file) = self.files.get_mut(&path) {
+            file.inputs = rendered.inputs;
+        }
+        Ok(())
+    }
+
+    fn symlink<P, Q>(&mut self, path: P, target: Q) -> anyhow::Result<()>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        let target = target.as_ref().as_os_str().as_bytes();
+        self.files.insert(
+            home(path)?,
+            schema::File {
+                sha1: Sha1::digest(target).into(),
+                mode: misc::S_IFLNK | 0o777,
+                inputs: BTreeMap::new(),
+                extra: target.to_vec(),
+            },
+        );
+        Ok(())
+    }
+
+    fn overrides<P>(&mut self, path: P, package_name: &str) -> anyhow::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        self.overrides.insert(home(path)?, package_name.to_owned());
+        Ok(())
+    }
+
+    fn when<P>(&mut self, path: P, conditions: &[schema::Condition]) -> anyhow::Result<bool>
+    where
+        P: AsRef<Path>,
+    {
+        let unmet = condition::unmet(conditions)?;
+        if unmet.is_empty() {
+            Ok(true)
+        } else {
+            let path = home(path)?;
+            tracing::warn!(package.name = self.name, ?path, ?unmet, "skipped");
+            self.unmet.insert(path, unmet);
+            Ok(false)
+        }
+    }
+
+    fn aggregate<P>(&mut self, path: P, aggregate: schema::Aggregate) -> anyhow::Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        self.aggregates.insert(home(path)?, aggregate);
+        Ok(())
+    }
+
+    fn fragment<P, C>(&mut self, target: P, key: &str, content: C) -> anyhow::Result<()>
+    where
+        P: AsRef<Path>,
+        C: AsRef<[u8]>,
+    {
+        let target = home(target)?;
+        // keys order the fragments and name them in a drop-in directory
+        anyhow::ensure!(
+            !key.is_empty() && !key.contains('/') && !key.starts_with('.'),
+            "invalid fragment key `{key}`",
+        );
+        let fragments = self.fragments.entry(target.clone()).or_default();
+        anyhow::ensure!(
+            fragments
+                .insert(key.to_owned(), content.as_ref().to_vec())
+                .is_none(),
+            "duplicate fragment `{key}` of `{}`",
+            target.display(),
+        );
+        Ok(())
+    }
+
     fn pre_install<I>(&mut self, command: I)
     where
         I: IntoIterator,
@@ -449,3 +900,16 @@ impl PackageExt for Package {
         });
     }
 }
+
+pub fn home<P>(path: P) -> anyhow::Result<PathBuf>
+where
+    P: AsRef<Path>,
+{
+    if path.as_ref().is_relative() {
+        Ok(dirs::home_dir()
+            .ok_or_else(|| anyhow::format_err!("missing home_dir"))?
+            .join(path))
+    } else {
+        Ok(path.as_ref().to_path_buf())
+    }
+}
@@ -0,0 +1,195 @@
+use crate::{misc, schema};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::Infallible;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub fn new(
+    data_path: &Path,
+    selection_path: &Path,
+    before: &schema::State<()>,
+    after: &schema::State<Vec<u8>>,
+    orphan: &BTreeSet<&Path>,
+    reinstall: &BTreeSet<String>,
+    only: &BTreeSet<String>,
+) -> anyhow::Result<schema::Plan> {
+    // the selection the plan was made from, or its absence
+    let mut paths = BTreeSet::from([data_path, selection_path]);
+    let mut rerendered = BTreeMap::new();
+    for (_, before, after) in crate::diff(before, after, reinstall) {
+        if let (Some(before), Some(after)) = (before, after) {
+            for (path, changes) in crate::rerendered(before, after) {
+                let changes = changes
+                    .into_iter()
+                    .map(|(input, (before, after))| {
+                        let change = schema::Change {
+                            before: before.map(str::to_owned),
+                            after: after.map(str::to_owned),
+                        };
+                        (input.to_owned(), change)
+                    })
+                    .collect();
+                rerendered.insert(path.to_path_buf(), changes);
+            }
+        }
+        if let Some(before) = before {
+            paths.extend(before.files.keys().map(PathBuf::as_path));
+        }
+        if let Some(after) = after {
+            paths.extend(after.files.keys().map(PathBuf::as_path));
+        }
+    }
+    paths.extend(orphan);
+    let symlinks = symlinks(before)
+        .chain(symlinks(after))
+        .collect::<BTreeSet<_>>();
+
+    let mut contents = BTreeMap::new();
+    Ok(schema::Plan {
+        before: before.clone(),
+        after: after.try_map(|path, file| {
+            contents.insert(path.to_path_buf(), file.extra.clone());
+            Ok::<_, Infallible>(())
+        })?,
+        orphan: orphan.iter().map(|path| path.to_path_buf()).collect(),
+        reinstall: reinstall.clone(),
+        only: only.clone(),
+        contents,
+        preconditions: paths
+            .into_iter()
+            .map(|path| {
+                let sha1 = misc::stat(path, symlinks.contains(path))?.map(|(sha1, _)| sha1);
+                Ok((path.to_path_buf(), sha1))
+            })
+            .collect::<io::Result<_>>()?,
+        selection: None,
+        missing: BTreeMap::new(),
+        unmet: BTreeMap::new(),
+        failed: BTreeMap::new(),
+        rerendered,
+    })
+}
+
+pub fn load(
+    path: &Path,
+    data_path: &Path,
+) -> anyhow::Result<(schema::Plan, schema::State<Vec<u8>>)> {
+    let mut plan = serde_json::from_reader::<_, schema::Plan>(File::open(path)?)?;
+    anyhow::ensure!(
+        plan.preconditions.contains_key(data_path),
+        "plan was created for another state `{}`",
+        data_path.display(),
+    );
+
+    let symlinks = symlinks(&plan.before)
+        .chain(symlinks(&plan.after))
+        .collect::<BTreeSet<_>>();
+    let mut mismatched = Vec::new();
+    for (path, expected) in &plan.preconditions {
+        let actual = misc::stat(path, symlinks.contains(path.as_path()))?.map(|(sha1, _)| sha1);
+        if actual != *expected {
+            tracing::error!(
+                ?path,
+                actual.sha1 = actual.map(hex::encode),
+                expected.sha1 = expected.map(hex::encode),
+                "precondition",
+            );
+            mismatched.push(path);
+        }
+    }
+    anyhow::ensure!(
+        mismatched.is_empty(),
+        "plan is outdated: {mismatched:?} changed",
+    );
+
+    let after = plan.after.try_map(|path, _| {
+        plan.contents
+            .remove(path)
+            .ok_or_else(|| anyhow::format_err!("missing content of `{}`", path.display()))
+    })?;
+    Ok((plan, after))
+}
+
+// paths managed as symlinks, whose preconditions are their targets
+fn symlinks<T>(state: &schema::State<T>) -> impl Iterator<Item = &Path> {
+    state
+        .packages
+        .iter()
+        .flat_map(|package| &package.files)
+        .filter(|(_, file)| file.mode & misc::S_IFMT == misc::S_IFLNK)
+        .map(|(path, _)| path.as_path())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha1::{Digest, Sha1};
+    use std::{env, fs, process};
+
+    fn file<T>(content: &[u8], extra: T) -> schema::File<T> {
+        schema::File {
+            sha1: Sha1::digest(content).into(),
+            mode: 0o100644,
+            inputs: BTreeMap::new(),
+            extra,
+        }
+    }
+
+    fn state<T>(files: BTreeMap<PathBuf, schema::File<T>>) -> schema::State<T> {
+        let mut package = schema::Package::new("package");
+        package.files = files;
+        schema::State {
+            packages: vec![package],
+        }
+    }
+
+    #[test]
+    fn preconditions() {
+        let dir = env::temp_dir().join(format!("akabei-plan-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data_path = dir.join("akabei.json");
+        let selection_path = dir.join("packages.toml");
+        let (changed, created) = (dir.join("changed"), dir.join("created"));
+        fs::write(&data_path, "{}").unwrap();
+        fs::write(&changed, "before").unwrap();
+        let _ = fs::remove_file(&created);
+        let _ = fs::remove_file(&selection_path);
+
+        let before = state(BTreeMap::from([(changed.clone(), file(b"before", ()))]));
+        let after = state(BTreeMap::from([
+            (changed.clone(), file(b"after", b"after".to_vec())),
+            (created.clone(), file(b"created", b"created".to_vec())),
+        ]));
+        let plan_path = dir.join("plan.json");
+        let empty = BTreeSet::new();
+        let plan = new(
+            &data_path,
+            &selection_path,
+            &before,
+            &after,
+            &BTreeSet::new(),
+            &empty,
+            &empty,
+        )
+        .unwrap();
+        assert_eq!(plan.preconditions[&created], None);
+        assert_eq!(plan.preconditions[&selection_path], None);
+        fs::write(&plan_path, serde_json::to_vec(&plan).unwrap()).unwrap();
+
+        let (_, loaded) = load(&plan_path, &data_path).unwrap();
+        assert_eq!(loaded.packages[0].files[&changed].extra, b"after");
+        assert!(load(&plan_path, &dir.join("other.json")).is_err());
+
+        fs::write(&created, "created").unwrap();
+        assert!(load(&plan_path, &data_path).is_err());
+        fs::remove_file(&created).unwrap();
+        fs::write(&changed, "changed").unwrap();
+        assert!(load(&plan_path, &data_path).is_err());
+        fs::write(&changed, "before").unwrap();
+        fs::write(&selection_path, "packages = []").unwrap();
+        assert!(load(&plan_path, &data_path).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+use crate::{misc, schema, selection};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{self, Path};
+
+// files are rendered as `load` does, with the active profile and the pins
+pub fn repair(
+    state: &mut schema::State<()>,
+    targets: &[String],
+    selection: Option<&schema::Selection>,
+    profile_name: Option<&str>,
+    confirm: bool,
+    hooks: bool,
+    apply: bool,
+) -> anyhow::Result<()> {
+    let mut package_names = BTreeSet::new();
+    let mut paths = BTreeSet::new();
+    for target in targets {
+        if state.packages.iter().any(|package| &package.name == target) {
+            package_names.insert(target.as_str());
+        } else {
+            paths.insert(path::absolute(target)?);
+        }
+    }
+
+    let profile = selection
+        .map(|selection| selection::profile(selection, profile_name))
+        .transpose()?
+        .flatten()
+        .map(|(_, profile)| profile);
+    let (theme, fonts) = crate::appearance(selection, profile)?;
+    let (after, _, _, _) = crate::load(
+        state.packages.iter().map(|package| package.name.clone()),
+        state,
+        |package_name| {
+            state
+                .packages
+                .iter()
+                .find(|package| package.name == package_name)
+                .map(|package| package.options.clone())
+                .unwrap_or_default()
+        },
+        &selection.map_or_else(BTreeMap::new, |selection| selection.pins.clone()),
+        &theme,
+        &fonts,
+        false,
+    )?;
+    // targets are validated before anything is installed
+    let known = after
+        .packages
+        .iter()
+        .flat_map(|package| package.files.keys())
+        .collect::<BTreeSet<_>>();
+    if let Some(path) = paths.iter().find(|path| !known.contains(path)) {
+        anyhow::bail!("unknown target `{}`", path.display());
+    }
+    for before in &mut state.packages {
+        let Some(after) = after
+            .packages
+            .iter()
+            .find(|package| package.name == before.name)
+        else {
+            continue;
+        };
+        let _span = tracing::info_span!("repair", package.name = before.name).entered();
+
+        let mut files = Vec::new();
+        for (path, file) in &after.files {
+            if !(targets.is_empty()
+                || package_names.contains(before.name.as_str())
+                || paths.contains(path))
+            {
+                continue;
+            }
+            if !drifted(path, file)? {
+                continue;
+            }
+            if apply && confirm && !misc::confirm(format_args!("repair {}?", path.display()))? {
+                continue;
+            }
+            files.push((path, file));
+        }
+        if files.is_empty() {
+            continue;
+        }
+
+        if hooks {
+            let _span = tracing::info_span!("pre_install").entered();
+            for hook in &after.hooks.pre_install {
+                misc::exec(&hook.command, apply)?;
+            }
+        }
+        for (path, file) in files {
+            misc::install(path, &file.extra, file.mode, apply)?;
+            before.files.insert(
+                path.clone(),
+                schema::File {
+                    sha1: file.sha1,
+                    mode: file.mode,
+                    inputs: file.inputs.clone(),
+                    extra: (),
+                },
+            );
+        }
+        if hooks {
+            let _span = tracing::info_span!("post_install").entered();
+            for hook in &after.hooks.post_install {
+                misc::exec(&hook.command, apply)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[tracing::instrument(err, ret, skip(file))]
+fn drifted<T>(path: &Path, file: &schema::File<T>) -> anyhow::Result<bool> {
+    Ok(misc::stat(path, file.mode & misc::S_IFMT == misc::S_IFLNK)?
+        .is_none_or(|(sha1, mode)| sha1 != file.sha1 || mode != file.mode))
+}
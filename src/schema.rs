@@ -1,8 +1,8 @@
 use crate::misc;
 use serde::{Deserialize, Deserializer, Serialize, de};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(bound(deserialize = "T: Default"))]
@@ -10,6 +10,44 @@ pub struct State<T> {
     pub packages: Vec<Package<T>>,
 }
 
+impl<T> State<T> {
+    pub fn try_map<U, E, F>(&self, mut f: F) -> Result<State<U>, E>
+    where
+        F: FnMut(&Path, &File<T>) -> Result<U, E>,
+    {
+        let packages = self
+            .packages
+            .iter()
+            .map(|package| {
+                let files = package
+                    .files
+                    .iter()
+                    .map(|(path, file)| {
+                        let file = File {
+                            sha1: file.sha1,
+                            mode: file.mode,
+                            inputs: file.inputs.clone(),
+                            extra: f(path, file)?,
+                        };
+                        Ok((path.clone(), file))
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(Package {
+                    name: package.name.clone(),
+                    files,
+                    hooks: package.hooks.clone(),
+                    options: package.options.clone(),
+                    depends: package.depends.clone(),
+                    aggregates: package.aggregates.clone(),
+                    fragments: package.fragments.clone(),
+                    dropins: package.dropins.clone(),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(State { packages })
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(bound(deserialize = "T: Default"))]
 pub struct Package<T> {
@@ -17,21 +55,55 @@ pub struct Package<T> {
     pub files: BTreeMap<PathBuf, File<T>>,
     #[serde(default)]
     pub hooks: Hooks,
+    #[serde(default, skip_serializing_if = "Options::is_empty")]
+    pub options: Options,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub depends: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub aggregates: BTreeMap<PathBuf, Aggregate>,
+    // the last fragments contributed to file aggregates,
+    // which compose them while this package is kept as it was
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fragments: BTreeMap<PathBuf, BTreeMap<String, String>>,
+    // the files of this package contributed to drop-in directories
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub dropins: BTreeSet<PathBuf>,
+}
+
+pub type Options = BTreeMap<String, toml::Value>;
+
+// an empty package for tests to fill in
+#[cfg(test)]
+impl<T> Package<T> {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            files: BTreeMap::new(),
+            hooks: Hooks::default(),
+            options: Options::new(),
+            depends: BTreeSet::new(),
+            aggregates: BTreeMap::new(),
+            fragments: BTreeMap::new(),
+            dropins: BTreeSet::new(),
+        }
+    }
 }
 
 #[serde_with::serde_as]
-#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct File<T> {
     #[serde_as(as = "serde_with::hex::Hex")]
     pub sha1: [u8; 20],
     #[serde_as(as = "misc::Octal")]
     pub mode: u32,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub inputs: BTreeMap<String, String>,
     #[serde(skip)]
     pub extra: T,
 }
 
 #[serde_with::serde_as]
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct Hooks {
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub pre_install: Vec<Hook>,
@@ -43,7 +115,7 @@ pub struct Hooks {
     pub post_remove: Vec<Hook>,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Hook {
     #[serde(deserialize_with = "deserialize_command")]
     pub command: Vec<String>,
@@ -79,3 +151,205 @@ where
 
     deserializer.deserialize_any(Visitor)
 }
+
+pub type Secrets = BTreeMap<String, SecretCommand>;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SecretCommand {
+    #[serde(deserialize_with = "deserialize_command")]
+    pub command: Vec<String>,
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_timeout() -> u64 {
+    10
+}
+
+#[serde_with::serde_as]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Plan {
+    pub before: State<()>,
+    pub after: State<()>,
+    pub orphan: BTreeSet<PathBuf>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub reinstall: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub only: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selection: Option<Selection>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub missing: BTreeMap<String, BTreeSet<String>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub unmet: BTreeMap<String, Unmet>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub failed: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub rerendered: BTreeMap<PathBuf, BTreeMap<String, Change>>,
+    #[serde_as(as = "BTreeMap<_, serde_with::hex::Hex>")]
+    pub contents: BTreeMap<PathBuf, Vec<u8>>,
+    #[serde_as(as = "BTreeMap<_, Option<serde_with::hex::Hex>>")]
+    pub preconditions: BTreeMap<PathBuf, Option<[u8; 20]>>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Change {
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Selection {
+    #[serde(default)]
+    pub packages: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub options: BTreeMap<String, Options>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profiles: BTreeMap<String, Profile>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pins: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub theme: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fonts: Option<Fonts>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Profile {
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub hosts: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub machine_ids: BTreeSet<String>,
+    #[serde(default)]
+    pub packages: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub options: BTreeMap<String, Options>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub theme: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fonts: Option<Fonts>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Fonts {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mono: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sans: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub emoji: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cjk: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u32>,
+}
+
+pub type Palette = BTreeMap<String, String>;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Theme {
+    pub name: String,
+    #[serde(flatten)]
+    pub palette: Palette,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Manifest {
+    #[serde(flatten)]
+    pub metadata: Metadata,
+    #[serde(default)]
+    pub depends: BTreeSet<String>,
+    #[serde(default)]
+    pub files: Vec<ManifestFile>,
+    #[serde(default)]
+    pub hooks: Hooks,
+    #[serde(default)]
+    pub overrides: BTreeMap<PathBuf, String>,
+    #[serde(default)]
+    pub options: Options,
+    #[serde(default)]
+    pub aggregates: BTreeMap<PathBuf, Aggregate>,
+    #[serde(default)]
+    pub fragments: Vec<ManifestFragment>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Metadata {
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub requires: BTreeSet<String>,
+    #[serde(default)]
+    pub optional: BTreeSet<String>,
+    #[serde(default)]
+    pub provides: BTreeSet<String>,
+    #[serde(default)]
+    pub when: Vec<Condition>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    Exists(PathBuf),
+    Command(String),
+    Installed(String),
+    Env(String),
+    Hostname(String),
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Unmet {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub package: Vec<Condition>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub files: BTreeMap<PathBuf, Vec<Condition>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ManifestFile {
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub content: ManifestContent,
+    pub mode: Option<u32>,
+    pub format: Option<Format>,
+    #[serde(default)]
+    pub when: Vec<Condition>,
+}
+
+// an aggregate is composed of the fragments every package contributes to it,
+// either into one file or into one file per fragment in a directory
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregate {
+    File,
+    Dir,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ManifestFragment {
+    pub target: PathBuf,
+    pub key: String,
+    #[serde(flatten)]
+    pub content: ManifestContent,
+    pub format: Option<Format>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    Shell,
+    Toml,
+    Json,
+    Ini,
+    Systemd,
+    Xml,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ManifestContent {
+    Source(PathBuf),
+    Template(PathBuf),
+    Symlink(PathBuf),
+}
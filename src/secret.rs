@@ -0,0 +1,230 @@
+use crate::{misc, schema};
+use anyhow::Context as _;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const REDACTED: &str = "<redacted>";
+
+// decrypted values, kept for the rest of the run so that they can be redacted
+static SECRETS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+static RESOLVE_COMMANDS: AtomicBool = AtomicBool::new(false);
+static KEY: OnceLock<[u8; 32]> = OnceLock::new();
+static COMMANDS: OnceLock<schema::Secrets> = OnceLock::new();
+
+// commands are not run in a dry run since they may prompt or have side effects
+pub fn resolve_commands(enabled: bool) {
+    RESOLVE_COMMANDS.store(enabled, Ordering::Relaxed);
+}
+
+// a command configured in `<config_dir>/akabei/secrets.toml` takes precedence over
+// `<config_dir>/akabei/secrets/<name>.age`, decrypted with `<config_dir>/akabei/identity.txt`;
+// `None` if the secret comes from a command that is not run
+pub fn get(name: &str) -> anyhow::Result<Option<String>> {
+    if let Some(value) = SECRETS.lock().unwrap().get(name) {
+        return Ok(Some(value.clone()));
+    }
+    anyhow::ensure!(
+        !name.is_empty() && !name.contains(['/', '\\']) && !name.starts_with('.'),
+        "invalid secret name `{name}`",
+    );
+    let config_dir = misc::config_dir()?;
+    let value = if let Some(command) = commands(&config_dir)?.get(name) {
+        if !RESOLVE_COMMANDS.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        run(name, command)?
+    } else {
+        decrypt(&config_dir, name)?
+    };
+    // a trailing newline is an artifact of `echo ... | age` or `pass show`
+    let value = value.strip_suffix('\n').unwrap_or(&value).to_owned();
+    SECRETS
+        .lock()
+        .unwrap()
+        .insert(name.to_owned(), value.clone());
+    Ok(Some(value))
+}
+
+// read once per run
+fn commands(config_dir: &Path) -> anyhow::Result<&'static schema::Secrets> {
+    if let Some(commands) = COMMANDS.get() {
+        return Ok(commands);
+    }
+    let path = config_dir.join("secrets.toml");
+    let commands = if path.try_exists()? {
+        toml::from_str(&fs::read_to_string(&path)?)
+            .with_context(|| format!("failed to parse `{}`", path.display()))?
+    } else {
+        schema::Secrets::new()
+    };
+    Ok(COMMANDS.get_or_init(|| commands))
+}
+
+fn decrypt(config_dir: &Path, name: &str) -> anyhow::Result<String> {
+    let identity = config_dir.join("identity.txt");
+    let identities = age::IdentityFile::from_file(identity.to_string_lossy().into_owned())
+        .with_context(|| format!("failed to read `{}`", identity.display()))?
+        .into_identities()?;
+    let path = config_dir.join("secrets").join(format!("{name}.age"));
+    let ciphertext =
+        fs::read(&path).with_context(|| format!("failed to read `{}`", path.display()))?;
+    let mut value = String::new();
+    age::Decryptor::new_buffered(age::armor::ArmoredReader::new(BufReader::new(
+        &ciphertext[..],
+    )))
+    .and_then(|decryptor| decryptor.decrypt(identities.iter().map(|identity| &**identity)))
+    .with_context(|| format!("failed to decrypt `{}`", path.display()))?
+    .read_to_string(&mut value)
+    .with_context(|| format!("failed to decrypt `{}`", path.display()))?;
+    Ok(value)
+}
+
+fn run(name: &str, secret: &schema::SecretCommand) -> anyhow::Result<String> {
+    let _span = tracing::info_span!("secret", secret.name = name).entered();
+    tracing::info!(command = ?secret.command, "run");
+    let (program, args) = secret
+        .command
+        .split_first()
+        .ok_or_else(|| anyhow::format_err!("empty command of secret `{name}`"))?;
+    // in a group of its own, so that a timeout kills whatever it spawned as well
+    let mut child = Command::new(program)
+        .args(args)
+        .stdout(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .with_context(|| format!("failed to run `{program}`"))?;
+    // read in another thread so that a full pipe does not stall the command;
+    // the pipe stays open as long as anything spawned by the command holds it
+    let mut stdout = child.stdout.take().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut value = String::new();
+        let _ = sender.send(stdout.read_to_string(&mut value).map(|_| value));
+    });
+    let deadline = Instant::now() + Duration::from_secs(secret.timeout);
+    let timeout = |child: &mut Child| -> anyhow::Result<String> {
+        let pgid = nix::unistd::Pid::from_raw(child.id() as _);
+        nix::sys::signal::killpg(pgid, nix::sys::signal::Signal::SIGKILL)?;
+        child.wait()?;
+        anyhow::bail!(
+            "command of secret `{name}` timed out after {}s",
+            secret.timeout,
+        )
+    };
+    let Ok(value) = receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+    else {
+        return timeout(&mut child);
+    };
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            return timeout(&mut child);
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    anyhow::ensure!(
+        status.success(),
+        "command of secret `{name}` failed with {status}",
+    );
+    Ok(value?)
+}
+
+// keyed with a random key of this host, so that a recorded hash of a short secret
+// cannot be brute-forced from the state alone
+pub fn hash(value: &str) -> anyhow::Result<String> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key()?)?;
+    mac.update(value.as_bytes());
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+// created even in a dry run, so that the hashes of a plan hold once it is applied
+fn key() -> anyhow::Result<&'static [u8; 32]> {
+    if let Some(key) = KEY.get() {
+        return Ok(key);
+    }
+    let path = crate::data_path()?.with_extension("key");
+    let key = match fs::read(&path) {
+        Ok(key) => key
+            .try_into()
+            .map_err(|_| anyhow::format_err!("invalid key `{}`", path.display()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut key = [0; 32];
+            File::open("/dev/urandom")?.read_exact(&mut key)?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)
+                .and_then(|mut file| file.write_all(&key))
+                .with_context(|| format!("failed to write `{}`", path.display()))?;
+            key
+        }
+        Err(e) => return Err(e).with_context(|| format!("failed to read `{}`", path.display())),
+    };
+    Ok(KEY.get_or_init(|| key))
+}
+
+// names of the secrets used by a file, from its recorded inputs
+pub fn names(inputs: &BTreeMap<String, String>) -> impl Iterator<Item = &str> {
+    inputs.keys().filter_map(|key| key.strip_prefix("secret."))
+}
+
+// whether every secret recorded in `inputs` is known with the recorded value,
+// i.e. `redact` covers all of them
+pub fn known(inputs: &BTreeMap<String, String>) -> bool {
+    names(inputs).all(|name| {
+        get(name).is_ok_and(|value| {
+            value.is_some_and(|value| {
+                hash(&value).is_ok_and(|hash| inputs.get(&format!("secret.{name}")) == Some(&hash))
+            })
+        })
+    })
+}
+
+pub fn redact(s: &str) -> Cow<'_, str> {
+    let secrets = SECRETS.lock().unwrap();
+    // longer values first, in case one secret contains another
+    let mut values = secrets
+        .values()
+        .filter(|value| !value.is_empty())
+        .collect::<Vec<_>>();
+    values.sort_by_key(|value| std::cmp::Reverse(value.len()));
+    let mut s = Cow::Borrowed(s);
+    for value in values {
+        if s.contains(value.as_str()) {
+            s = Cow::Owned(s.replace(value.as_str(), REDACTED));
+        }
+    }
+    s
+}
+
+// writes `tracing` output with the values of secrets redacted
+pub struct Redacted;
+
+impl Write for Redacted {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stdout().write_all(redact(&String::from_utf8_lossy(buf)).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
@@ -0,0 +1,351 @@
+use crate::{misc, schema, theme};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub fn path() -> anyhow::Result<PathBuf> {
+    Ok(misc::config_dir()?.join("packages.toml"))
+}
+
+pub fn read(path: &Path) -> anyhow::Result<Option<schema::Selection>> {
+    if path.try_exists()? {
+        Ok(Some(toml::from_str(&fs::read_to_string(path)?)?))
+    } else {
+        Ok(None)
+    }
+}
+
+#[tracing::instrument(err, skip(selection))]
+pub fn write(path: &Path, selection: &schema::Selection) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, toml::to_string(selection)?)?;
+    Ok(())
+}
+
+// edits the selection file in place, keeping its comments and formatting,
+// and leaves it alone when the selection did not change
+#[tracing::instrument(err, skip(selection))]
+pub fn update(path: &Path, selection: &schema::Selection) -> anyhow::Result<()> {
+    let content = fs::read_to_string(path)?;
+    let before = toml::Table::try_from(toml::from_str::<schema::Selection>(&content)?)?;
+    let after = toml::Table::try_from(selection)?;
+    if before == after {
+        return Ok(());
+    }
+    let mut document = content.parse::<toml_edit::DocumentMut>()?;
+    edit(document.as_table_mut(), &before, &after)?;
+    fs::write(path, document.to_string())?;
+    Ok(())
+}
+
+fn edit(
+    table: &mut dyn toml_edit::TableLike,
+    before: &toml::Table,
+    after: &toml::Table,
+) -> anyhow::Result<()> {
+    for key in before.keys() {
+        if !after.contains_key(key) {
+            table.remove(key);
+        }
+    }
+    for (key, value) in after {
+        match (before.get(key), value, table.get_mut(key)) {
+            (Some(before), _, Some(_)) if before == value => (),
+            (Some(toml::Value::Table(before)), toml::Value::Table(after), Some(item))
+                if item.is_table_like() =>
+            {
+                edit(item.as_table_like_mut().unwrap(), before, after)?;
+            }
+            // sets of names keep the order and comments of the names still in them
+            (Some(toml::Value::Array(_)), toml::Value::Array(after), Some(item))
+                if item.is_array() =>
+            {
+                let array = item.as_array_mut().unwrap();
+                let mut kept = Vec::new();
+                array.retain(|element| {
+                    let element = plain(element);
+                    let keep = element
+                        .as_ref()
+                        .is_some_and(|element| after.contains(element));
+                    kept.extend(element.filter(|_| keep));
+                    keep
+                });
+                // a name is added on its own line when the others are
+                let indent = array.iter().last().and_then(|last| {
+                    let prefix = last.decor().prefix()?.as_str()?;
+                    let (_, indent) = prefix.rsplit_once('\n')?;
+                    Some(format!("\n{indent}"))
+                });
+                for element in after {
+                    if !kept.contains(element) {
+                        let mut element = element.to_string().parse::<toml_edit::Value>()?;
+                        if let Some(indent) = &indent {
+                            element.decor_mut().set_prefix(indent.as_str());
+                            array.push_formatted(element);
+                        } else {
+                            array.push(element);
+                        }
+                    }
+                }
+            }
+            _ => {
+                table.insert(key, item(value)?);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn plain(value: &toml_edit::Value) -> Option<toml::Value> {
+    let mut value = value.clone();
+    value.decor_mut().clear();
+    value.to_string().parse().ok()
+}
+
+fn item(value: &toml::Value) -> anyhow::Result<toml_edit::Item> {
+    Ok(match value {
+        toml::Value::Table(table) => {
+            let mut item = toml_edit::Table::new();
+            // only the tables holding values get a header
+            item.set_implicit(true);
+            for (key, value) in table {
+                item.insert(key, self::item(value)?);
+            }
+            toml_edit::Item::Table(item)
+        }
+        value => toml_edit::Item::Value(value.to_string().parse()?),
+    })
+}
+
+pub fn profile<'a>(
+    selection: &'a schema::Selection,
+    profile_name: Option<&str>,
+) -> anyhow::Result<Option<(&'a String, &'a schema::Profile)>> {
+    if let Some(profile_name) = profile_name {
+        let profile = selection
+            .profiles
+            .get_key_value(profile_name)
+            .ok_or_else(|| anyhow::format_err!("missing profile `{profile_name}`"))?;
+        return Ok(Some(profile));
+    }
+
+    let hostname = nix::unistd::gethostname()?.to_string_lossy().into_owned();
+    let machine_id = match fs::read_to_string("/etc/machine-id") {
+        Ok(machine_id) => Some(machine_id.trim().to_owned()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    let mut profiles = selection.profiles.iter().filter(|(_, profile)| {
+        profile.hosts.contains(&hostname)
+            || machine_id
+                .as_ref()
+                .is_some_and(|machine_id| profile.machine_ids.contains(machine_id))
+    });
+    let profile = profiles.next();
+    if let Some((profile_name, _)) = profile {
+        let others = profiles
+            .map(|(profile_name, _)| profile_name)
+            .collect::<BTreeSet<_>>();
+        anyhow::ensure!(
+            others.is_empty(),
+            "host matches multiple profiles: `{profile_name}`, {others:?}",
+        );
+    }
+    Ok(profile)
+}
+
+pub fn options(
+    selection: &schema::Selection,
+    profile: Option<&schema::Profile>,
+    package_name: &str,
+) -> schema::Options {
+    let mut options = selection
+        .options
+        .get(package_name)
+        .cloned()
+        .unwrap_or_default();
+    if let Some(profile) = profile
+        && let Some(profile_options) = profile.options.get(package_name)
+    {
+        options.extend(profile_options.clone());
+    }
+    options
+}
+
+// a profile may switch the theme of the whole selection
+pub fn theme<'a>(
+    selection: &'a schema::Selection,
+    profile: Option<&'a schema::Profile>,
+) -> &'a str {
+    profile
+        .and_then(|profile| profile.theme.as_deref())
+        .or(selection.theme.as_deref())
+        .unwrap_or(theme::DEFAULT)
+}
+
+// fonts of a profile override those of the selection one by one
+pub fn fonts(selection: &schema::Selection, profile: Option<&schema::Profile>) -> schema::Fonts {
+    let fonts = selection.fonts.clone().unwrap_or_default();
+    let Some(profile) = profile.and_then(|profile| profile.fonts.clone()) else {
+        return fonts;
+    };
+    schema::Fonts {
+        mono: profile.mono.or(fonts.mono),
+        sans: profile.sans.or(fonts.sans),
+        emoji: profile.emoji.or(fonts.emoji),
+        cjk: profile.cjk.or(fonts.cjk),
+        size: profile.size.or(fonts.size),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    #[test]
+    fn round_trip() {
+        let dir = env::temp_dir().join(format!("akabei-selection-{}", process::id()));
+        let path = dir.join("akabei/packages.toml");
+        assert!(read(&path).unwrap().is_none());
+
+        let selection =
+            toml::from_str::<schema::Selection>(r#"packages = ["base", "tmux"]"#).unwrap();
+        write(&path, &selection).unwrap();
+        let read = read(&path).unwrap().unwrap();
+        assert_eq!(read.packages, selection.packages);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn update_in_place() {
+        let dir = env::temp_dir().join(format!("akabei-update-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("packages.toml");
+        let content = r#"# packages of every host
+packages = [
+    # always
+    "base",
+    "tmux",
+]
+
+[profiles.work]
+hosts = ["desk"] # the office
+packages = ["sway"]
+"#;
+        fs::write(&path, content).unwrap();
+        let mut selection = read(&path).unwrap().unwrap();
+        update(&path, &selection).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), content);
+
+        selection.packages.remove("tmux");
+        selection.packages.insert("zsh".to_owned());
+        let work = selection.profiles.get_mut("work").unwrap();
+        work.options.insert(
+            "sway".to_owned(),
+            schema::Options::from([("gaps".to_owned(), toml::Value::from(4))]),
+        );
+        work.packages.insert("foot".to_owned());
+        selection.pins.insert("uid".to_owned(), "1000".to_owned());
+        update(&path, &selection).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            r#"# packages of every host
+packages = [
+    # always
+    "base",
+    "zsh",
+]
+
+[profiles.work]
+hosts = ["desk"] # the office
+packages = ["sway", "foot"]
+
+[profiles.work.options.sway]
+gaps = 4
+
+[pins]
+uid = "1000"
+"#,
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn selection() -> schema::Selection {
+        toml::from_str(
+            r#"
+            packages = ["base"]
+            theme = "nord"
+
+            [options.tmux]
+            prefix = "C-a"
+            mouse = true
+
+            [fonts]
+            mono = "Hack"
+            size = 12
+
+            [profiles.work]
+            packages = ["sway"]
+            theme = "dracula"
+
+            [profiles.work.options.tmux]
+            prefix = "C-b"
+
+            [profiles.work.fonts]
+            size = 14
+
+            [profiles.home]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn named_profile() {
+        let selection = selection();
+        let (profile_name, work) = profile(&selection, Some("work")).unwrap().unwrap();
+        assert_eq!(profile_name, "work");
+        assert!(work.packages.contains("sway"));
+        assert!(profile(&selection, Some("missing")).is_err());
+    }
+
+    #[test]
+    fn options_of_profile() {
+        let selection = selection();
+        let work = &selection.profiles["work"];
+        let merged = options(&selection, Some(work), "tmux");
+        assert_eq!(merged["prefix"].as_str(), Some("C-b"));
+        assert_eq!(merged["mouse"].as_bool(), Some(true));
+        let merged = options(&selection, None, "tmux");
+        assert_eq!(merged["prefix"].as_str(), Some("C-a"));
+        assert!(options(&selection, Some(work), "sway").is_empty());
+    }
+
+    #[test]
+    fn theme_of_profile() {
+        let selection = selection();
+        assert_eq!(
+            theme(&selection, Some(&selection.profiles["work"])),
+            "dracula"
+        );
+        assert_eq!(theme(&selection, Some(&selection.profiles["home"])), "nord");
+        assert_eq!(theme(&schema::Selection::default(), None), theme::DEFAULT);
+    }
+
+    #[test]
+    fn fonts_of_profile() {
+        let selection = selection();
+        let merged = fonts(&selection, Some(&selection.profiles["work"]));
+        assert_eq!(merged.mono.as_deref(), Some("Hack"));
+        assert_eq!(merged.size, Some(14));
+        assert_eq!(merged.sans, None);
+        let merged = fonts(&selection, Some(&selection.profiles["home"]));
+        assert_eq!(merged.size, Some(12));
+    }
+}
@@ -0,0 +1,69 @@
+use crate::misc;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::process::{Command, Stdio};
+
+pub fn missing(
+    requires: &BTreeMap<String, BTreeSet<String>>,
+) -> anyhow::Result<BTreeMap<String, BTreeSet<String>>> {
+    let names = requires.values().flatten().collect::<BTreeSet<_>>();
+    if names.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    // `pacman -Qq` prints the installed ones and fails if any is missing
+    let output = match Command::new("pacman")
+        .arg("-Qq")
+        .args(&names)
+        .stderr(Stdio::null())
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            tracing::warn!("missing `pacman`, skipping system package check");
+            return Ok(BTreeMap::new());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let installed = String::from_utf8(output.stdout)?
+        .lines()
+        .map(str::to_owned)
+        .collect::<BTreeSet<_>>();
+
+    let mut missing = BTreeMap::new();
+    for (package_name, requires) in requires {
+        let names = requires
+            .difference(&installed)
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        if !names.is_empty() {
+            let _span = tracing::info_span!("package", package.name = package_name).entered();
+            tracing::warn!(?names, "missing system packages");
+            missing.insert(package_name.clone(), names);
+        }
+    }
+    Ok(missing)
+}
+
+pub fn installed(name: &str) -> io::Result<bool> {
+    match Command::new("pacman")
+        .args(["-Qq", name])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+    {
+        Ok(status) => Ok(status.success()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+pub fn install(missing: &BTreeMap<String, BTreeSet<String>>, apply: bool) -> anyhow::Result<()> {
+    let names = missing.values().flatten().collect::<BTreeSet<_>>();
+    if names.is_empty() {
+        return Ok(());
+    }
+    let _span = tracing::info_span!("system").entered();
+    let mut command = vec!["paru", "-S", "--needed"];
+    command.extend(names.into_iter().map(String::as_str));
+    misc::exec(command, apply)
+}
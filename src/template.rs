@@ -0,0 +1,515 @@
+use crate::context::{Context, Recorder};
+use crate::{misc, schema, secret};
+use anyhow::Context as _;
+use minijinja::value::{Enumerator, Object, ObjectRepr, Value, ValueKind};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub type Inputs = BTreeMap<String, String>;
+// the files of the packages loaded so far, shared by every package being loaded
+pub type Files = Arc<Mutex<BTreeMap<String, BTreeMap<PathBuf, Vec<u8>>>>>;
+
+// the library compiled templates import, and runtime ones unless overridden under `dir()`
+const LIBRARY: &[(&str, &str)] = &[(
+    "packages/lib/common.jinja",
+    include_str!("packages/lib/common.jinja"),
+)];
+
+pub const UNRESOLVED: &str = "<unresolved>";
+
+// raised when a template includes a file of a package that is not loaded yet
+#[derive(Debug)]
+pub struct Pending(pub String);
+
+impl fmt::Display for Pending {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "package `{}` is not loaded yet", self.0)
+    }
+}
+
+impl error::Error for Pending {}
+
+pub struct Rendered {
+    pub content: String,
+    pub inputs: Inputs,
+    pub secret: bool,
+}
+
+pub fn dir() -> anyhow::Result<PathBuf> {
+    Ok(misc::config_dir()?.join("templates"))
+}
+
+// a file under `dir()` replaces the compiled template of the same path
+pub fn runtime(path: &str) -> anyhow::Result<Option<(PathBuf, String)>> {
+    let path = dir()?.join(path);
+    match fs::read_to_string(&path) {
+        Ok(source) => {
+            tracing::debug!(?path, "runtime template");
+            Ok(Some((path, source)))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("failed to read `{}`", path.display())),
+    }
+}
+
+// the format of the escaper a compiled template is declared with
+pub fn format(escape: &str) -> Option<schema::Format> {
+    match escape {
+        "shell" => Some(schema::Format::Shell),
+        "toml" => Some(schema::Format::Toml),
+        "json" => Some(schema::Format::Json),
+        "ini" => Some(schema::Format::Ini),
+        "systemd" => Some(schema::Format::Systemd),
+        "xml" => Some(schema::Format::Xml),
+        _ => None,
+    }
+}
+
+macro_rules! escaper {
+    ($name:ident, $format:expr) => {
+        #[derive(Clone, Copy)]
+        #[allow(dead_code)]
+        pub struct $name;
+
+        impl askama::filters::Escaper for $name {
+            fn write_escaped_str<W>(&self, mut dest: W, s: &str) -> fmt::Result
+            where
+                W: fmt::Write,
+            {
+                let s = escape($format, s).map_err(|e| {
+                    tracing::error!("{e:#}");
+                    fmt::Error
+                })?;
+                dest.write_str(&s)
+            }
+        }
+    };
+}
+
+// registered in `askama.toml` as the escapers of compiled templates,
+// whether or not one is declared with the format yet
+escaper!(Shell, schema::Format::Shell);
+escaper!(Toml, schema::Format::Toml);
+escaper!(Json, schema::Format::Json);
+escaper!(Ini, schema::Format::Ini);
+escaper!(Systemd, schema::Format::Systemd);
+escaper!(Xml, schema::Format::Xml);
+
+// `{{ value|shell }}` and so on escape a value regardless of the declared format
+#[allow(dead_code)]
+pub mod filters {
+    use super::escape;
+    use crate::schema;
+    use askama::filters::Safe;
+    use std::fmt::{self, Write};
+
+    fn filter<T>(format: schema::Format, value: T) -> askama::Result<Safe<String>>
+    where
+        T: fmt::Display,
+    {
+        let mut s = String::new();
+        write!(s, "{value}")?;
+        escape(format, &s)
+            .map(Safe)
+            .map_err(|e| askama::Error::Custom(e.into()))
+    }
+
+    pub fn shell<T>(value: T, _: &dyn askama::Values) -> askama::Result<Safe<String>>
+    where
+        T: fmt::Display,
+    {
+        filter(schema::Format::Shell, value)
+    }
+
+    pub fn toml<T>(value: T, _: &dyn askama::Values) -> askama::Result<Safe<String>>
+    where
+        T: fmt::Display,
+    {
+        filter(schema::Format::Toml, value)
+    }
+
+    pub fn json<T>(value: T, _: &dyn askama::Values) -> askama::Result<Safe<String>>
+    where
+        T: fmt::Display,
+    {
+        filter(schema::Format::Json, value)
+    }
+
+    pub fn ini<T>(value: T, _: &dyn askama::Values) -> askama::Result<Safe<String>>
+    where
+        T: fmt::Display,
+    {
+        filter(schema::Format::Ini, value)
+    }
+
+    pub fn systemd<T>(value: T, _: &dyn askama::Values) -> askama::Result<Safe<String>>
+    where
+        T: fmt::Display,
+    {
+        filter(schema::Format::Systemd, value)
+    }
+
+    pub fn xml<T>(value: T, _: &dyn askama::Values) -> askama::Result<Safe<String>>
+    where
+        T: fmt::Display,
+    {
+        filter(schema::Format::Xml, value)
+    }
+}
+
+// an error a compiled template ran into is recorded, since askama only sees `fmt::Error`
+pub fn compiled(
+    path: &Path,
+    content: askama::Result<String>,
+    context: &Context,
+) -> anyhow::Result<Rendered> {
+    finish(context, content.map_err(anyhow::Error::from))
+        .with_context(|| format!("failed to render `{}`", path.display()))
+}
+
+pub fn render(
+    path: &Path,
+    source: &str,
+    context: &Context,
+    format: Option<schema::Format>,
+) -> anyhow::Result<Rendered> {
+    let name = path.to_string_lossy();
+    let mut env = minijinja::Environment::new();
+    env.set_undefined_behavior(minijinja::UndefinedBehavior::Strict);
+    for (name, format) in [
+        ("shell", schema::Format::Shell),
+        ("toml", schema::Format::Toml),
+        ("json", schema::Format::Json),
+        ("ini", schema::Format::Ini),
+        ("systemd", schema::Format::Systemd),
+        ("xml", schema::Format::Xml),
+    ] {
+        env.add_filter(name, move |value: Value| {
+            escape(format, &value.to_string())
+                .map(Value::from_safe_string)
+                .map_err(invalid)
+        });
+    }
+    env.set_loader(|name| match runtime(name) {
+        Ok(Some((_, source))) => Ok(Some(source)),
+        Ok(None) => Ok(LIBRARY
+            .iter()
+            .find(|(path, _)| *path == name)
+            .map(|(_, source)| (*source).to_owned())),
+        Err(e) => Err(minijinja::Error::new(
+            minijinja::ErrorKind::TemplateNotFound,
+            format!("{e:#}"),
+        )),
+    });
+    {
+        let context = context.clone();
+        env.add_function("package_file", move |package: String, path: String| {
+            let s = context.package_file(&package, &path);
+            raise(&context).map(|()| Value::from_safe_string(s.0))
+        });
+    }
+    {
+        let context = context.clone();
+        env.add_function("secret", move |name: String| {
+            let s = context.secret(&name);
+            raise(&context).map(|()| s)
+        });
+    }
+    // values are escaped for the declared format unless marked `safe`;
+    // a custom auto-escape also marks macro output as safe
+    if let Some(format) = format {
+        let name = match format {
+            schema::Format::Shell => "shell",
+            schema::Format::Toml => "toml",
+            schema::Format::Json => "json",
+            schema::Format::Ini => "ini",
+            schema::Format::Systemd => "systemd",
+            schema::Format::Xml => "xml",
+        };
+        env.set_auto_escape_callback(move |_| minijinja::AutoEscape::Custom(name));
+    }
+    env.set_formatter(move |out, state, value| match format {
+        Some(format) if !value.is_safe() => out
+            .write_str(&escape(format, &value.to_string()).map_err(invalid)?)
+            .map_err(minijinja::Error::from),
+        _ => minijinja::escape_formatter(out, state, value),
+    });
+    let root = Tracked {
+        path: None,
+        value: Value::from_serialize(context),
+        recorder: context.recorder.clone(),
+    };
+    let content = env
+        .add_template(&name, source)
+        .and_then(|()| env.get_template(&name)?.render(Value::from_object(root)))
+        .map_err(anyhow::Error::from);
+    finish(context, content).with_context(|| format!("failed to render `{}`", path.display()))
+}
+
+fn finish(context: &Context, content: anyhow::Result<String>) -> anyhow::Result<Rendered> {
+    let inputs = context.recorder.finish()?;
+    let mut content = content?;
+    if !content.ends_with('\n') {
+        content.push('\n');
+    }
+    // a file of another package may bring in a secret as well
+    let secret = secret::names(&inputs).next().is_some()
+        || matches!(secret::redact(&content), Cow::Owned(_));
+    Ok(Rendered {
+        content,
+        inputs,
+        secret,
+    })
+}
+
+// an error recorded by a function of the context fails a runtime template right away
+fn raise(context: &Context) -> Result<(), minijinja::Error> {
+    match context.recorder.error() {
+        Some(e) => {
+            let pending = e.downcast_ref::<Pending>().map(|e| Pending(e.0.clone()));
+            let error = invalid(e);
+            Err(match pending {
+                Some(pending) => error.with_source(pending),
+                None => error,
+            })
+        }
+        None => Ok(()),
+    }
+}
+
+fn invalid(e: anyhow::Error) -> minijinja::Error {
+    minijinja::Error::new(minijinja::ErrorKind::InvalidOperation, format!("{e:#}"))
+}
+
+fn escape(format: schema::Format, s: &str) -> anyhow::Result<String> {
+    // the contents of a double-quoted string; JSON escapes are valid in TOML too
+    let quoted = serde_json::to_string(s)?;
+    match format {
+        schema::Format::Shell => {
+            if !s.is_empty()
+                && s.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c))
+            {
+                Ok(s.to_owned())
+            } else {
+                Ok(format!("'{}'", s.replace('\'', r"'\''")))
+            }
+        }
+        schema::Format::Toml | schema::Format::Json => Ok(quoted[1..quoted.len() - 1].to_owned()),
+        schema::Format::Ini => {
+            if s.contains(['\n', '\r']) {
+                Err(anyhow::format_err!("INI values cannot contain newlines"))
+            } else if s.trim() != s || s.contains([';', '#', '"']) {
+                Ok(quoted)
+            } else {
+                Ok(s.to_owned())
+            }
+        }
+        schema::Format::Systemd => {
+            if s.is_empty() || s.contains(|c: char| c.is_whitespace() || "\"'\\".contains(c)) {
+                Ok(quoted.replace('%', "%%"))
+            } else {
+                Ok(s.replace('%', "%%"))
+            }
+        }
+        schema::Format::Xml => Ok(s
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+            .replace('\'', "&apos;")),
+    }
+}
+
+pub fn changes<'a>(before: &'a Inputs, after: &'a Inputs) -> crate::Changes<'a> {
+    let mut changes = BTreeMap::<_, (_, _)>::new();
+    for (key, value) in before {
+        changes.entry(key.as_str()).or_default().0 = Some(value.as_str());
+    }
+    for (key, value) in after {
+        changes.entry(key.as_str()).or_default().1 = Some(value.as_str());
+    }
+    changes.retain(|_, (before, after)| before != after);
+    changes
+}
+
+// records every leaf value looked up by a runtime template
+#[derive(Debug)]
+struct Tracked {
+    path: Option<String>,
+    value: Value,
+    recorder: Recorder,
+}
+
+impl Object for Tracked {
+    fn repr(self: &Arc<Self>) -> ObjectRepr {
+        ObjectRepr::Map
+    }
+
+    fn get_value(self: &Arc<Self>, key: &Value) -> Option<Value> {
+        let key = key.as_str().map_or_else(|| key.to_string(), str::to_owned);
+        let path = match &self.path {
+            Some(path) => format!("{path}.{key}"),
+            None => key.clone(),
+        };
+        let value = match self.recorder.pins().get(&path) {
+            Some(value) => Value::from(value.clone()),
+            None => self.value.get_item(&Value::from(key)).ok()?,
+        };
+        if value.is_undefined() {
+            None
+        } else if value.kind() == ValueKind::Map {
+            Some(Value::from_object(Self {
+                path: Some(path),
+                value,
+                recorder: self.recorder.clone(),
+            }))
+        } else {
+            self.recorder.record(path, value.to_string());
+            Some(value)
+        }
+    }
+
+    fn enumerate(self: &Arc<Self>) -> Enumerator {
+        match self.value.try_iter() {
+            Ok(keys) => Enumerator::Values(keys.collect()),
+            Err(_) => Enumerator::Empty,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_shell() {
+        assert_eq!(escape(schema::Format::Shell, "a/b-c.d").unwrap(), "a/b-c.d");
+        assert_eq!(escape(schema::Format::Shell, "").unwrap(), "''");
+        assert_eq!(escape(schema::Format::Shell, "a b").unwrap(), "'a b'");
+        assert_eq!(escape(schema::Format::Shell, "it's").unwrap(), r"'it'\''s'");
+        assert_eq!(escape(schema::Format::Shell, "$HOME").unwrap(), "'$HOME'");
+    }
+
+    #[test]
+    fn escape_toml_json() {
+        for format in [schema::Format::Toml, schema::Format::Json] {
+            assert_eq!(escape(format, "plain").unwrap(), "plain");
+            assert_eq!(escape(format, "a\"b\\c\nd").unwrap(), r#"a\"b\\c\nd"#);
+        }
+    }
+
+    #[test]
+    fn escape_ini() {
+        assert_eq!(
+            escape(schema::Format::Ini, "Hack:size=12").unwrap(),
+            "Hack:size=12"
+        );
+        assert_eq!(
+            escape(schema::Format::Ini, " padded").unwrap(),
+            r#"" padded""#
+        );
+        assert_eq!(escape(schema::Format::Ini, "a;b").unwrap(), r#""a;b""#);
+        assert!(escape(schema::Format::Ini, "a\nb").is_err());
+    }
+
+    #[test]
+    fn escape_systemd() {
+        assert_eq!(
+            escape(schema::Format::Systemd, "/usr/bin/foo").unwrap(),
+            "/usr/bin/foo"
+        );
+        assert_eq!(escape(schema::Format::Systemd, "100%").unwrap(), "100%%");
+        assert_eq!(
+            escape(schema::Format::Systemd, "a b%").unwrap(),
+            r#""a b%%""#
+        );
+        assert_eq!(escape(schema::Format::Systemd, "").unwrap(), r#""""#);
+    }
+
+    #[test]
+    fn escape_xml() {
+        assert_eq!(
+            escape(schema::Format::Xml, r#"<a href="x">'&'</a>"#).unwrap(),
+            "&lt;a href=&quot;x&quot;&gt;&apos;&amp;&apos;&lt;/a&gt;",
+        );
+    }
+
+    #[test]
+    fn runtime_inputs() {
+        let mut package = crate::packages::Package::new("tool");
+        package
+            .declared
+            .insert("scratch".to_owned(), toml::Value::from("/scratch"));
+        package.pins.insert("uid".to_owned(), "1000".to_owned());
+        let context = Context::new(&package).unwrap();
+        let rendered = render(
+            Path::new("tool.conf"),
+            "{{ options.scratch }}/{{ package }}-{{ uid }}",
+            &context,
+            None,
+        )
+        .unwrap();
+        assert_eq!(rendered.content, "/scratch/tool-1000\n");
+        assert_eq!(
+            rendered.inputs,
+            Inputs::from([
+                ("options.scratch".to_owned(), "/scratch".to_owned()),
+                ("package".to_owned(), "tool".to_owned()),
+                ("uid".to_owned(), "1000".to_owned()),
+            ]),
+        );
+
+        let mut after = rendered.inputs.clone();
+        after.insert("uid".to_owned(), "1001".to_owned());
+        after.remove("package");
+        assert_eq!(
+            changes(&rendered.inputs, &after),
+            BTreeMap::from([
+                ("package", (Some("tool"), None)),
+                ("uid", (Some("1000"), Some("1001"))),
+            ]),
+        );
+    }
+
+    #[test]
+    fn library_and_package_file() {
+        let mut package = crate::packages::Package::new("tool");
+        package
+            .declared
+            .insert("scratch".to_owned(), toml::Value::from("/scratch"));
+        package.pins.insert("uid".to_owned(), "1000".to_owned());
+        let source = r#"{% import "packages/lib/common.jinja" as lib -%}
+{{ lib.scratch("tool") }} {{ package_file("base", ".bashrc") }}"#;
+        let err = render(
+            Path::new("tool.conf"),
+            source,
+            &Context::new(&package).unwrap(),
+            None,
+        )
+        .err()
+        .unwrap();
+        assert!(err.chain().any(|e| e.is::<Pending>()));
+
+        let bashrc = crate::packages::home(".bashrc").unwrap();
+        package.loaded.lock().unwrap().insert(
+            "base".to_owned(),
+            BTreeMap::from([(bashrc, b"set -o vi".to_vec())]),
+        );
+        let rendered = render(
+            Path::new("tool.conf"),
+            source,
+            &Context::new(&package).unwrap(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(rendered.content, "/scratch/tool-1000 set -o vi\n");
+        assert!(rendered.inputs.contains_key("package_file.base:.bashrc"));
+    }
+}
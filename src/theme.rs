@@ -0,0 +1,50 @@
+use crate::{misc, schema};
+use anyhow::Context as _;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+pub const DEFAULT: &str = "default-dark";
+
+const BUILTIN: &[(&str, &str)] = &[
+    ("default-dark", include_str!("themes/default-dark.toml")),
+    ("dracula", include_str!("themes/dracula.toml")),
+    ("gruvbox-dark", include_str!("themes/gruvbox-dark.toml")),
+    ("solarized-dark", include_str!("themes/solarized-dark.toml")),
+];
+
+pub fn dir() -> anyhow::Result<PathBuf> {
+    Ok(misc::config_dir()?.join("themes"))
+}
+
+// a base16 palette in `dir()/<name>.toml` takes precedence over the builtin one
+pub fn load(name: &str) -> anyhow::Result<schema::Theme> {
+    let path = dir()?.join(format!("{name}.toml"));
+    let source = match fs::read_to_string(&path) {
+        Ok(source) => source,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => BUILTIN
+            .iter()
+            .find(|(builtin, _)| *builtin == name)
+            .map(|(_, source)| (*source).to_owned())
+            .ok_or_else(|| anyhow::format_err!("unknown theme `{name}`"))?,
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to read `{}`", path.display()));
+        }
+    };
+    let palette = toml::from_str::<schema::Palette>(&source)
+        .with_context(|| format!("failed to parse theme `{name}`"))?;
+    for i in 0..16 {
+        let key = format!("base{i:02X}");
+        let color = palette
+            .get(&key)
+            .ok_or_else(|| anyhow::format_err!("missing `{key}` in theme `{name}`"))?;
+        anyhow::ensure!(
+            color.len() == 6 && color.chars().all(|c| c.is_ascii_hexdigit()),
+            "invalid `{key}` in theme `{name}`: `{color}`",
+        );
+    }
+    Ok(schema::Theme {
+        name: name.to_owned(),
+        palette,
+    })
+}
@@ -0,0 +1,105 @@
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::{env, process};
+
+// a home with a package requiring system packages,
+// and stand-ins of `pacman` and `paru` on PATH
+struct Home {
+    dir: PathBuf,
+}
+
+impl Home {
+    fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!("akabei-{name}-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join(".local/share")).unwrap();
+        let package = dir.join(".config/akabei/packages/tool");
+        fs::create_dir_all(&package).unwrap();
+        fs::write(
+            package.join("package.toml"),
+            "requires = [\"fake-installed\", \"fake-missing\"]\n",
+        )
+        .unwrap();
+
+        let bin = dir.join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        // `pacman -Qq` prints the installed ones and fails if any is missing
+        script(
+            &bin.join("pacman"),
+            r#"status=0
+for name in "$@"; do
+    case "$name" in
+        -Qq) ;;
+        fake-installed) echo "$name" ;;
+        *) status=1 ;;
+    esac
+done
+exit "$status""#,
+        );
+        script(&bin.join("paru"), r#"echo "$@" >> "$HOME/paru.log""#);
+        Self { dir }
+    }
+
+    fn akabei(&self, args: &[&str]) -> Output {
+        let path = env::join_paths(
+            [self.dir.join("bin")]
+                .into_iter()
+                .chain(env::split_paths(&env::var_os("PATH").unwrap_or_default())),
+        )
+        .unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_akabei"))
+            .args(args)
+            .current_dir(&self.dir)
+            .env("HOME", &self.dir)
+            .env("PATH", path)
+            .env("NO_COLOR", "1")
+            .env_remove("XDG_CONFIG_HOME")
+            .env_remove("XDG_DATA_HOME")
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr),
+        );
+        output
+    }
+
+    fn paru(&self) -> Option<String> {
+        fs::read_to_string(self.dir.join("paru.log")).ok()
+    }
+}
+
+impl Drop for Home {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn script(path: &Path, body: &str) {
+    fs::write(path, format!("#!/bin/sh\n{body}\n")).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
+
+#[test]
+fn missing() {
+    let home = Home::new("missing");
+    let plan = home.dir.join("plan.json");
+    home.akabei(&["plan", "--out", plan.to_str().unwrap(), "--install", "tool"]);
+    let plan = serde_json::from_slice::<serde_json::Value>(&fs::read(plan).unwrap()).unwrap();
+    assert_eq!(
+        plan["missing"],
+        serde_json::json!({ "tool": ["fake-missing"] })
+    );
+}
+
+#[test]
+fn install() {
+    let home = Home::new("install");
+    home.akabei(&["--install", "tool", "--with-system-deps"]);
+    assert_eq!(home.paru(), None);
+    home.akabei(&["--install", "tool", "--with-system-deps", "--apply"]);
+    assert_eq!(home.paru().as_deref(), Some("-S --needed fake-missing\n"));
+}